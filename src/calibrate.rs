@@ -0,0 +1,129 @@
+//! Measuring how far off frequency a tuner is, against a carrier whose
+//! frequency is known, like a broadcast transmitter or a signal generator.
+
+use crate::config::Config;
+use crate::fft::SimpleFft;
+use crate::source::{self, FileSource, SampleSource};
+use anyhow::ensure;
+use log::{info, warn};
+use rtlsdr_rs::DEFAULT_BUF_LENGTH;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Width of the FFT; at 2.4 MS/s, bins are about 37 Hz wide.
+const FFT_SIZE: usize = 1 << 16;
+
+/// Furthest from the reference to look for it, in ppm; well beyond even the
+/// cheapest dongles' crystals.
+const SEARCH_PPM: f64 = 200.;
+
+/// How far above the noise floor the carrier must be to be trusted, in dB.
+const MIN_SNR_DB: f64 = 10.;
+
+/// Measure the carrier at `reference` Hz for `seconds`, from `recording` or
+/// else the configured input, and print the ppm correction it suggests.
+pub fn calibrate(
+    shutdown: &AtomicBool,
+    mut config: Config,
+    reference: u32,
+    recording: Option<&Path>,
+    seconds: f64,
+) -> anyhow::Result<()> {
+    ensure!(seconds > 0., "must measure for some time, not {seconds}s");
+    config.hop = None;
+    let mut source: Box<dyn SampleSource + '_> = match recording {
+        // Made wherever they say, or else at --frequency, as when scanning
+        Some(path) => Box::new(FileSource::open(path, &config)?),
+        None => {
+            config.frequency = reference;
+            source::open(&config, shutdown)?
+        }
+    };
+    let info = source.info();
+    // Whatever the samples were already corrected by
+    let ppm = info.tuning.ppm.unwrap_or(config.ppm);
+    let centre = f64::from(info.radio.capture_freq);
+    let rate = f64::from(info.radio.capture_rate);
+    let offset = f64::from(reference) - centre;
+    let search = f64::from(reference) * SEARCH_PPM / 1e6;
+    ensure!(
+        offset.abs() + search < rate / 2.,
+        "reference {reference} Hz isn't within the {rate} S/s captured around {centre} Hz"
+    );
+    info!("Measuring the carrier at {reference} Hz for {seconds}s");
+
+    // Average power in each bin, with the centre frequency in the middle
+    let mut fft = SimpleFft::new(FFT_SIZE);
+    let mut power = vec![0f64; FFT_SIZE];
+    let mut frames = 0;
+    let wanted = (seconds * rate) as usize / FFT_SIZE;
+    let mut buf = Box::new([0; DEFAULT_BUF_LENGTH]);
+    let result = loop {
+        if frames >= wanted || shutdown.load(Ordering::Relaxed) {
+            break Ok(());
+        }
+        match source.read(&mut buf) {
+            Ok(Some(_)) => {}
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+        for chunk in buf.chunks_exact(2 * FFT_SIZE) {
+            let magnitudes = fft.process(chunk);
+            for (bin, magnitude) in magnitudes.into_iter().enumerate() {
+                power[(bin + FFT_SIZE / 2) % FFT_SIZE] += f64::from(magnitude).powi(2);
+            }
+            frames += 1;
+        }
+    };
+    let closed = source.close();
+    result.and(closed)?;
+    ensure!(frames > 0, "no samples to measure");
+    if frames < wanted {
+        warn!(
+            "Only measured for {:.1}s",
+            (frames * FFT_SIZE) as f64 / rate
+        );
+    }
+
+    // The strongest bin anywhere the carrier could be
+    let bin_width = rate / FFT_SIZE as f64;
+    let bin_of =
+        |freq: f64| ((freq / bin_width).round() as isize + (FFT_SIZE / 2) as isize) as usize;
+    let range = bin_of(offset - search)..=bin_of(offset + search);
+    let peak = range
+        .clone()
+        .max_by(|&a, &b| power[a].total_cmp(&power[b]))
+        .expect("search range is never empty");
+
+    let mut floor = power[range].to_vec();
+    floor.sort_by(f64::total_cmp);
+    let snr_db = 10. * (power[peak] / floor[floor.len() / 2]).log10();
+    ensure!(
+        snr_db >= MIN_SNR_DB,
+        "no carrier found near {reference} Hz; the strongest signal is only {snr_db:.1} dB above the noise"
+    );
+
+    // Fit a parabola through the peak and its neighbours to find where it
+    // falls between bins
+    let before = peak.checked_sub(1).and_then(|bin| power.get(bin));
+    let fraction = match (before, power.get(peak + 1)) {
+        (Some(&before), Some(&after)) => {
+            let (before, at, after) = (before.log10(), power[peak].log10(), after.log10());
+            0.5 * (before - after) / (before - 2. * at + after)
+        }
+        _ => 0.,
+    };
+    let measured = centre + (peak as f64 - (FFT_SIZE / 2) as f64 + fraction) * bin_width;
+
+    // A crystal running fast tunes high, so the carrier appears low
+    let error = measured - f64::from(reference);
+    let suggested = f64::from(ppm) - error / f64::from(reference) * 1e6;
+    println!(
+        "Carrier at {reference} Hz measured at {measured:.1} Hz ({error:+.1} Hz), {snr_db:.1} dB above the noise"
+    );
+    println!(
+        "Suggested ppm: {} (measured with {ppm}; {suggested:.2} exactly)",
+        suggested.round()
+    );
+    Ok(())
+}
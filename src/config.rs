@@ -0,0 +1,851 @@
+use anyhow::{Context, bail, ensure};
+use clap::{Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The only version of the configuration file format we understand.
+const CONFIG_VERSION: u32 = 1;
+
+/// Watch an rtl-sdr device and save recordings of anything above the noise floor.
+///
+/// Settings come from the selected profiles in the configuration file, if any,
+/// and are then overridden by any flags given here.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Configuration file containing capture profiles
+    #[arg(short, long, default_value = "rtl-sdr-snipper.toml")]
+    pub config: PathBuf,
+
+    /// Name of the profile to load from the configuration file; give more than
+    /// once to watch several devices at once
+    #[arg(short, long)]
+    pub profile: Vec<String>,
+
+    /// Centre frequency to monitor, in Hz
+    #[arg(short, long)]
+    pub frequency: Option<u32>,
+
+    /// Sample rate to record at, in samples per second
+    #[arg(short, long)]
+    pub sample_rate: Option<u32>,
+
+    /// Where to read samples from: "device", "-" for rtl_sdr's output on stdin,
+    /// or "rtl_tcp://host:port"
+    #[arg(short, long)]
+    pub input: Option<Input>,
+
+    /// Index of the rtl-sdr device to open
+    #[arg(short, long, conflicts_with = "serial")]
+    pub device_index: Option<usize>,
+
+    /// USB serial number of the rtl-sdr device to open, instead of an index
+    #[arg(long)]
+    pub serial: Option<String>,
+
+    /// Tuner gain in dB, or "auto"; snapped to the nearest the tuner supports
+    #[arg(short, long)]
+    pub gain: Option<Gain>,
+
+    /// Whether to turn on the rtl2832's digital AGC, as well as the tuner's gain
+    #[arg(long, value_name = "BOOL")]
+    pub digital_agc: Option<bool>,
+
+    /// Frequency correction for the tuner's crystal, in ppm; see calibrate
+    #[arg(long, allow_negative_numbers = true)]
+    pub ppm: Option<i32>,
+
+    /// Whether to power an LNA or filter through the antenna input
+    #[arg(long, value_name = "BOOL")]
+    pub bias_tee: Option<bool>,
+
+    /// Sample an ADC input directly, bypassing the tuner, for HF
+    #[arg(long, value_enum)]
+    pub direct_sampling: Option<DirectSampling>,
+
+    /// Directory to write recordings into; created if missing
+    #[arg(short, long)]
+    pub output_dir: Option<PathBuf>,
+
+    /// Buffers of samples to queue for processing, if it falls behind
+    #[arg(long)]
+    pub queue_depth: Option<usize>,
+
+    /// What to do when the queue for processing is full
+    #[arg(long, value_enum)]
+    pub queue_full: Option<QueueFull>,
+
+    /// Share the samples with rtl_tcp clients, listening on this address
+    #[arg(long, value_name = "ADDRESS")]
+    pub serve: Option<String>,
+
+    /// What to do with tuning commands from rtl_tcp clients
+    #[arg(long, value_enum)]
+    pub client_commands: Option<ClientCommands>,
+
+    /// Whether to shift recordings back to the requested frequency
+    #[arg(long, value_enum)]
+    pub tuning_offset: Option<TuningOffset>,
+
+    /// Down-convert recordings to this sample rate, around --ddc-frequency
+    #[arg(long)]
+    pub ddc_rate: Option<u32>,
+
+    /// Centre of the signal to down-convert, in Hz; defaults to --frequency
+    #[arg(long)]
+    pub ddc_frequency: Option<u32>,
+
+    /// Sample format for down-converted recordings
+    #[arg(long, value_enum)]
+    pub ddc_format: Option<SampleFormat>,
+
+    /// Hop between these centre frequencies, in Hz, instead of watching --frequency
+    #[arg(long, value_delimiter = ',', value_name = "FREQUENCIES")]
+    pub hop: Option<Vec<u32>>,
+
+    /// How long to watch each frequency when hopping, in milliseconds
+    #[arg(long)]
+    pub dwell_ms: Option<u32>,
+
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(long, default_value_t = LevelFilter::Info)]
+    pub log_level: LevelFilter,
+
+    /// Print a spectrum summary for every FFT frame
+    #[arg(long)]
+    pub debug: bool,
+}
+
+/// Things to do other than watching a device.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the detector over an existing recording, instead of a device
+    ///
+    /// Reads .cu8, .wav (stereo I/Q) and SigMF recordings. Recordings which
+    /// don't say what frequency or sample rate they were made at are taken to be
+    /// at --frequency and --sample-rate.
+    Scan {
+        /// Recording to read
+        input: PathBuf,
+    },
+    /// List attached rtl-sdr devices, with the index and serial to pick them by
+    ListDevices,
+    /// Measure the tuner's frequency error against a carrier at a known
+    /// frequency, and suggest a --ppm correction for it
+    ///
+    /// The input is tuned to the reference as it would be to --frequency, with
+    /// the current --ppm applied.
+    Calibrate {
+        /// Frequency of the reference carrier, in Hz
+        reference: u32,
+        /// Recording containing the carrier to measure, instead of the input
+        #[arg(long)]
+        recording: Option<PathBuf>,
+        /// How long to measure for, in seconds
+        #[arg(long, default_value_t = 5.)]
+        seconds: f64,
+    },
+}
+
+/// Layout of the configuration file; see `rtl-sdr-snipper.example.toml`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    version: u32,
+    #[serde(default)]
+    profiles: BTreeMap<String, Config>,
+}
+
+/// Everything needed to run a capture, as loaded from a profile.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Centre frequency to monitor, in Hz
+    pub frequency: u32,
+    /// Sample rate to record at, in samples per second
+    pub sample_rate: u32,
+    pub input: Input,
+    pub device_index: usize,
+    /// USB serial number of the device to open; takes priority over the index
+    pub serial: Option<String>,
+    pub gain: Gain,
+    /// Turn on the rtl2832's digital AGC, which varies the level of the samples
+    pub digital_agc: bool,
+    /// Frequency correction for the tuner's crystal, in ppm
+    pub ppm: i32,
+    /// Power an LNA or filter through the antenna input, until we're done
+    pub bias_tee: bool,
+    pub direct_sampling: DirectSampling,
+    pub output_dir: PathBuf,
+    /// File name for recordings, without an extension; see `output_name()`
+    pub output_name: String,
+    /// Buffers of samples to queue for processing, each of 131072 samples
+    pub queue_depth: usize,
+    pub queue_full: QueueFull,
+    /// Address to serve the samples to rtl_tcp clients on, if any
+    pub serve: Option<String>,
+    pub client_commands: ClientCommands,
+    pub tuning_offset: TuningOffset,
+    /// Down-convert recordings before saving them, if set
+    pub ddc: Option<DdcConfig>,
+    /// Hop between several frequencies, instead of watching `frequency`, if set
+    pub hop: Option<HopConfig>,
+    /// Lead-in to record before the first detection of an event, in milliseconds
+    pub pre_roll_ms: u32,
+    /// Tail to record after the last detection of an event, in milliseconds;
+    /// the event is over once nothing has been detected for this long
+    pub post_roll_ms: u32,
+    /// Longest a single recording can be, in milliseconds; longer events are
+    /// split across several recordings
+    pub max_length_ms: u32,
+    pub detector: DetectorConfig,
+
+    /// Print a spectrum summary for every FFT frame
+    #[serde(skip)]
+    pub debug: bool,
+    /// Name of the profile, if the configuration came from one
+    #[serde(skip)]
+    pub name: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            frequency: 434_200_000,
+            sample_rate: 2_880_000,
+            input: Input::Device,
+            device_index: 0,
+            serial: None,
+            gain: Gain::Auto,
+            digital_agc: false,
+            ppm: 0,
+            bias_tee: false,
+            direct_sampling: DirectSampling::Off,
+            output_dir: PathBuf::from("."),
+            output_name: "snipper_{time}_{frequency}_{rate}".to_string(),
+            queue_depth: 64,
+            queue_full: QueueFull::DropOldest,
+            serve: None,
+            client_commands: ClientCommands::Ignore,
+            tuning_offset: TuningOffset::Remove,
+            ddc: None,
+            hop: None,
+            pre_roll_ms: 500,
+            post_roll_ms: 500,
+            max_length_ms: 60_000,
+            detector: DetectorConfig::default(),
+            debug: false,
+            name: String::new(),
+        }
+    }
+}
+
+/// Digital down-conversion of recordings to just the signal of interest.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DdcConfig {
+    /// Centre of the signal of interest, in Hz; defaults to the requested frequency
+    pub frequency: Option<u32>,
+    /// Sample rate to decimate to; rounded to a whole fraction of the capture rate
+    pub output_rate: u32,
+    /// Width of the low-pass filter, in Hz; defaults to 80% of the output rate
+    pub bandwidth: Option<u32>,
+    #[serde(default)]
+    pub format: SampleFormat,
+}
+
+/// Hopping between centre frequencies, to watch several bands with one device.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HopConfig {
+    /// Centre frequencies to visit in turn, in Hz
+    pub frequencies: Vec<u32>,
+    /// How long to watch each frequency for, in milliseconds, unless something
+    /// is happening there
+    #[serde(default = "HopConfig::default_dwell_ms")]
+    pub dwell_ms: u32,
+}
+
+impl HopConfig {
+    fn default_dwell_ms() -> u32 {
+        5_000
+    }
+}
+
+/// Thresholds deciding what counts as an event.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DetectorConfig {
+    /// How far above the noise floor a bin must be to be detected, in dB
+    pub threshold_db: f32,
+    /// FFT frames averaged into each spectrum the detector looks at
+    pub average: usize,
+    /// How the per-bin noise floor is estimated
+    pub floor: FloorMethod,
+    /// Time constant of the average, or length of the window the minimum is
+    /// taken over, in milliseconds
+    pub floor_window_ms: u32,
+    /// How long a bin can be detected before it is learnt into the noise floor
+    /// anyway, in milliseconds
+    pub hold_off_ms: u32,
+    /// Spectra with a detection needed in a buffer for it to count towards an event
+    pub min_spectra: usize,
+    /// Counting buffers needed in an event for it to be written out
+    pub min_buffers: usize,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        DetectorConfig {
+            threshold_db: 10.,
+            average: 16,
+            floor: FloorMethod::Average,
+            floor_window_ms: 1_000,
+            hold_off_ms: 10_000,
+            min_spectra: 2,
+            min_buffers: 2,
+        }
+    }
+}
+
+/// Ways of estimating the noise floor in each bin.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FloorMethod {
+    /// Exponential moving average
+    Average,
+    /// Minimum over a sliding window, which ignores short bursts entirely, but
+    /// sits below the typical noise level, so wants a higher threshold
+    Minimum,
+}
+
+impl Config {
+    /// Build a configuration for each of the selected profiles, or from the
+    /// defaults if none were selected, apply the command-line overrides, and
+    /// validate them.
+    pub fn load(args: &Args) -> anyhow::Result<Vec<Config>> {
+        let mut configs = Vec::new();
+        for name in &args.profile {
+            let mut config = Self::load_profile(&args.config, name)
+                .with_context(|| format!("loading {}", args.config.display()))?;
+            config.name = name.clone();
+            configs.push(config);
+        }
+        if configs.is_empty() {
+            configs.push(Config::default());
+        }
+
+        for config in &mut configs {
+            let checked = config.apply(args).and_then(|()| config.validate());
+            match config.name.as_str() {
+                "" => checked?,
+                name => checked.with_context(|| format!("in profile {name:?}"))?,
+            }
+        }
+
+        // Two profiles can't share a device, or write over each other's recordings
+        let mut devices = BTreeMap::new();
+        let mut outputs = BTreeMap::new();
+        for config in &configs {
+            let output = (&config.output_dir, &config.output_name);
+            if let Some(other) = outputs.insert(output, &config.name) {
+                ensure!(
+                    config.output_name.contains("{profile}"),
+                    "profiles {other:?} and {:?} both write {:?} to {}; add {{profile}} to the output name",
+                    config.name,
+                    config.output_name,
+                    config.output_dir.display()
+                );
+            }
+            let device = match (&config.input, &config.serial) {
+                (Input::Device, Some(serial)) => format!("serial {serial}"),
+                (Input::Device, None) => format!("index {}", config.device_index),
+                _ => continue,
+            };
+            if let Some(other) = devices.insert(device.clone(), &config.name) {
+                bail!(
+                    "profiles {other:?} and {:?} both use device {device}",
+                    config.name
+                );
+            }
+        }
+        Ok(configs)
+    }
+
+    /// Override the profile with any command-line flags given.
+    fn apply(&mut self, args: &Args) -> anyhow::Result<()> {
+        if let Some(frequency) = args.frequency {
+            self.frequency = frequency;
+        }
+        if let Some(sample_rate) = args.sample_rate {
+            self.sample_rate = sample_rate;
+        }
+        if let Some(input) = &args.input {
+            self.input = input.clone();
+        }
+        if let Some(device_index) = args.device_index {
+            self.device_index = device_index;
+            self.serial = None;
+        }
+        if let Some(serial) = &args.serial {
+            self.serial = Some(serial.clone());
+        }
+        if let Some(gain) = args.gain {
+            self.gain = gain;
+        }
+        if let Some(digital_agc) = args.digital_agc {
+            self.digital_agc = digital_agc;
+        }
+        if let Some(ppm) = args.ppm {
+            self.ppm = ppm;
+        }
+        if let Some(bias_tee) = args.bias_tee {
+            self.bias_tee = bias_tee;
+        }
+        if let Some(direct_sampling) = args.direct_sampling {
+            self.direct_sampling = direct_sampling;
+        }
+        if let Some(output_dir) = &args.output_dir {
+            self.output_dir = output_dir.clone();
+        }
+        if let Some(queue_depth) = args.queue_depth {
+            self.queue_depth = queue_depth;
+        }
+        if let Some(queue_full) = args.queue_full {
+            self.queue_full = queue_full;
+        }
+        if let Some(serve) = &args.serve {
+            self.serve = Some(serve.clone());
+        }
+        if let Some(client_commands) = args.client_commands {
+            self.client_commands = client_commands;
+        }
+        if let Some(tuning_offset) = args.tuning_offset {
+            self.tuning_offset = tuning_offset;
+        }
+        if let Some(output_rate) = args.ddc_rate {
+            let ddc = self.ddc.get_or_insert(DdcConfig {
+                frequency: None,
+                output_rate,
+                bandwidth: None,
+                format: SampleFormat::default(),
+            });
+            ddc.output_rate = output_rate;
+        }
+        if args.ddc_frequency.is_some() || args.ddc_format.is_some() {
+            let Some(ddc) = &mut self.ddc else {
+                bail!(
+                    "--ddc-frequency and --ddc-format need --ddc-rate, or a profile with ddc set"
+                );
+            };
+            if let Some(frequency) = args.ddc_frequency {
+                ddc.frequency = Some(frequency);
+            }
+            if let Some(format) = args.ddc_format {
+                ddc.format = format;
+            }
+        }
+        if let Some(frequencies) = &args.hop {
+            let hop = self.hop.get_or_insert(HopConfig {
+                frequencies: Vec::new(),
+                dwell_ms: HopConfig::default_dwell_ms(),
+            });
+            hop.frequencies = frequencies.clone();
+        }
+        if let Some(dwell_ms) = args.dwell_ms {
+            let Some(hop) = &mut self.hop else {
+                bail!("--dwell-ms needs --hop, or a profile with hop set");
+            };
+            hop.dwell_ms = dwell_ms;
+        }
+        // Start from the first frequency on the list
+        if let Some(&frequency) = self.hop.as_ref().and_then(|hop| hop.frequencies.first()) {
+            self.frequency = frequency;
+        }
+        self.debug = args.debug;
+        Ok(())
+    }
+
+    fn load_profile(path: &Path, name: &str) -> anyhow::Result<Config> {
+        let text = std::fs::read_to_string(path)?;
+        let file: ConfigFile = toml::from_str(&text)?;
+        ensure!(
+            file.version == CONFIG_VERSION,
+            "unsupported config version {}, expected {CONFIG_VERSION}",
+            file.version
+        );
+        let mut profiles = file.profiles;
+        match profiles.remove(name) {
+            Some(profile) => Ok(profile),
+            None => bail!(
+                "no profile named {name:?}, available: {:?}",
+                profiles.keys().collect::<Vec<_>>()
+            ),
+        }
+    }
+
+    /// Check the settings make sense for the hardware, and prepare the output directory.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            (1..=TUNER_MAX).contains(&self.frequency),
+            "frequency must be between 1 and {TUNER_MAX} Hz, not {}",
+            self.frequency
+        );
+        // optimal_settings() may raise lower rates, but the rtl2832 tops out here
+        ensure!(
+            (1..=3_200_000).contains(&self.sample_rate),
+            "sample rate must be between 1 and 3200000 S/s, not {}",
+            self.sample_rate
+        );
+        if let Gain::Manual(db) = self.gain {
+            ensure!(
+                (0.0..=60.0).contains(&db),
+                "gain must be between 0 and 60 dB, not {db}"
+            );
+        }
+        if let Some(serial) = &self.serial {
+            ensure!(!serial.is_empty(), "serial must not be empty");
+        }
+        ensure!(
+            self.ppm.abs() <= 1000,
+            "ppm must be between -1000 and 1000, not {}",
+            self.ppm
+        );
+        ensure!(
+            !self.bias_tee || self.input != Input::Stdin,
+            "the bias tee can't be turned on through stdin; use rtl_sdr -T"
+        );
+        ensure!(self.queue_depth > 0, "queue depth must be at least 1");
+        if self.direct_sampling != DirectSampling::Off {
+            ensure!(
+                self.input != Input::Stdin,
+                "direct sampling can't be turned on through stdin; use rtl_sdr -D"
+            );
+            let hops = self.hop.iter().flat_map(|hop| &hop.frequencies);
+            for &frequency in std::iter::once(&self.frequency).chain(hops) {
+                ensure!(
+                    frequency <= DIRECT_SAMPLING_MAX,
+                    "direct sampling only reaches {DIRECT_SAMPLING_MAX} Hz, not {frequency} Hz"
+                );
+            }
+        }
+
+        ensure!(
+            self.output_name.contains("{time}"),
+            "output name {:?} must contain {{time}}, or recordings would overwrite each other",
+            self.output_name
+        );
+        let mut rest = self.output_name.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                bail!("unclosed {{ in output name {:?}", self.output_name);
+            };
+            let placeholder = &rest[start..start + len + 1];
+            ensure!(
+                OUTPUT_PLACEHOLDERS.contains(&placeholder),
+                "unknown placeholder {placeholder} in output name, expected one of {OUTPUT_PLACEHOLDERS:?}"
+            );
+            rest = &rest[start + len + 1..];
+        }
+
+        if let Some(ddc) = &self.ddc {
+            ensure!(
+                ddc.output_rate > 0 && ddc.output_rate < self.sample_rate,
+                "ddc output rate must be below the sample rate, not {}",
+                ddc.output_rate
+            );
+            let bandwidth = ddc.bandwidth();
+            ensure!(
+                bandwidth > 0 && bandwidth <= ddc.output_rate,
+                "ddc bandwidth must be between 1 Hz and the output rate, not {bandwidth}"
+            );
+        }
+
+        if let Some(hop) = &self.hop {
+            ensure!(
+                hop.frequencies.len() >= 2,
+                "hopping needs at least two frequencies"
+            );
+            for &frequency in &hop.frequencies {
+                ensure!(
+                    (1..=TUNER_MAX).contains(&frequency),
+                    "hop frequencies must be between 1 and {TUNER_MAX} Hz, not {frequency}"
+                );
+            }
+            ensure!(hop.dwell_ms > 0, "hop dwell must be at least 1 ms");
+            // Unless it follows the tuning, the down-converted band has to be
+            // in every channel, or some recordings wouldn't contain it
+            if let Some(ddc) = &self.ddc
+                && let Some(ddc_frequency) = ddc.frequency
+            {
+                for &frequency in &hop.frequencies {
+                    let reach = ddc_frequency.abs_diff(frequency) + ddc.bandwidth() / 2;
+                    ensure!(
+                        reach <= self.sample_rate / 2,
+                        "ddc band around {ddc_frequency} Hz must be inside the {} Hz watched around every hop frequency, not outside {frequency} Hz",
+                        self.sample_rate
+                    );
+                }
+            }
+            ensure!(
+                self.input != Input::Stdin,
+                "can't hop when reading from stdin, as it can't be retuned"
+            );
+        }
+
+        ensure!(self.post_roll_ms > 0, "post-roll must be at least 1 ms");
+        ensure!(
+            self.max_length_ms > self.pre_roll_ms,
+            "max length must be longer than the pre-roll"
+        );
+        ensure!(
+            self.detector.threshold_db > 0.,
+            "detector threshold must be above 0 dB, not {}",
+            self.detector.threshold_db
+        );
+        ensure!(
+            self.detector.floor_window_ms > 0,
+            "detector floor window must be at least 1 ms"
+        );
+        ensure!(
+            self.detector.average > 0,
+            "detector average must be at least one frame"
+        );
+        ensure!(
+            self.detector.min_buffers > 0,
+            "detector min-buffers must be at least one"
+        );
+
+        std::fs::create_dir_all(&self.output_dir)
+            .with_context(|| format!("creating output directory {}", self.output_dir.display()))?;
+        ensure!(
+            self.output_dir.is_dir(),
+            "output path {} is not a directory",
+            self.output_dir.display()
+        );
+        Ok(())
+    }
+
+    /// Fill in the output name template for a recording started at `time`,
+    /// centred on `frequency`, which is `offset` Hz above the requested frequency.
+    pub fn output_name(&self, time: &str, frequency: u64, offset: i64, rate: u32) -> String {
+        self.output_name
+            .replace("{time}", time)
+            .replace("{frequency}", &frequency.to_string())
+            .replace("{offset}", &offset.to_string())
+            .replace("{rate}", &rate.to_string())
+            .replace("{profile}", &self.name)
+    }
+}
+
+const OUTPUT_PLACEHOLDERS: &[&str] = &["{time}", "{frequency}", "{offset}", "{rate}", "{profile}"];
+
+impl DdcConfig {
+    /// Centre of the signal of interest, given the requested `frequency`.
+    pub fn frequency(&self, frequency: u32) -> u32 {
+        self.frequency.unwrap_or(frequency)
+    }
+
+    /// Width of the low-pass filter, in Hz.
+    pub fn bandwidth(&self) -> u32 {
+        self.bandwidth.unwrap_or(self.output_rate / 10 * 8)
+    }
+}
+
+/// Sample formats we can write down-converted recordings in.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SampleFormat {
+    /// Complex 32-bit floats
+    #[default]
+    Cf32,
+    /// Complex signed 16-bit integers
+    Cs16,
+}
+
+impl SampleFormat {
+    /// The SigMF `core:datatype` for this format.
+    pub fn sigmf_datatype(self) -> &'static str {
+        match self {
+            SampleFormat::Cf32 => "cf32_le",
+            SampleFormat::Cs16 => "ci16_le",
+        }
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::Cf32 => 8,
+            SampleFormat::Cs16 => 4,
+        }
+    }
+}
+
+/// What to do about the radio being tuned away from the requested frequency,
+/// to keep the DC spike out of the way.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TuningOffset {
+    /// Record the samples as received, labelled with the frequency actually tuned
+    Record,
+    /// Shift the samples so they are centred on the requested frequency
+    Remove,
+}
+
+/// Which of the rtl2832's ADC inputs to sample directly, bypassing the tuner,
+/// for HF. The numbers are librtlsdr's.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DirectSampling {
+    /// Receive through the tuner, as usual
+    Off = 0,
+    /// Sample the I branch input, which most direct sampling mods use
+    I = 1,
+    /// Sample the Q branch input, as on the RTL-SDR Blog V3 and V4
+    Q = 2,
+}
+
+/// Highest frequency any of the tuners reach, the E4000; well short of where
+/// `optimal_settings()` adding its offset would overflow.
+pub const TUNER_MAX: u32 = 2_200_000_000;
+
+/// Highest frequency direct sampling can receive, by undersampling with the
+/// rtl2832's 28.8 MHz ADC clock.
+pub const DIRECT_SAMPLING_MAX: u32 = 28_800_000;
+
+/// What to do with samples when processing falls so far behind that its queue
+/// is full.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueFull {
+    /// Drop the oldest buffer, and mark the gap in any recording it was part of
+    DropOldest,
+    /// Wait for processing to catch up, leaving the device to drop samples
+    /// unseen
+    Block,
+}
+
+/// What to do with tuning commands from rtl_tcp clients.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientCommands {
+    /// Carry on as configured, whatever clients ask for
+    Ignore,
+    /// Retune as clients ask, and record wherever that ends up
+    Honour,
+}
+
+/// Where samples are read from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    /// A local rtl-sdr device, picked by index or serial
+    Device,
+    /// Samples piped in from `rtl_sdr -`, already tuned to the requested
+    /// frequency and rate
+    Stdin,
+    /// An rtl_tcp server, at this host and port, which we tune ourselves
+    RtlTcp(String),
+}
+
+/// Port rtl_tcp listens on unless told otherwise.
+pub const RTL_TCP_PORT: u16 = 1234;
+
+impl FromStr for Input {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s == "device" {
+            return Ok(Input::Device);
+        }
+        if s == "-" {
+            return Ok(Input::Stdin);
+        }
+        match s.strip_prefix("rtl_tcp://") {
+            Some("") => bail!("rtl_tcp input needs a host"),
+            Some(address) if address.contains(':') => Ok(Input::RtlTcp(address.to_string())),
+            Some(host) => Ok(Input::RtlTcp(format!("{host}:{RTL_TCP_PORT}"))),
+            None => bail!("expected \"device\", \"-\" or \"rtl_tcp://host:port\", not {s:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Device => f.write_str("device"),
+            Input::Stdin => f.write_str("-"),
+            Input::RtlTcp(address) => write!(f, "rtl_tcp://{address}"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Input {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Tuner gain setting.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Gain {
+    Auto,
+    /// Gain in dB
+    Manual(f32),
+}
+
+impl FromStr for Gain {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Gain::Auto);
+        }
+        match s.parse::<f32>() {
+            Ok(db) if db.is_finite() => Ok(Gain::Manual(db)),
+            _ => bail!("expected a gain in dB or 'auto', not {s:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Gain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Gain::Auto => f.write_str("auto"),
+            Gain::Manual(db) => write!(f, "{db}"),
+        }
+    }
+}
+
+/// Accepts either a number of dB, or the string "auto".
+impl<'de> Deserialize<'de> for Gain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Db(f32),
+            Name(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Db(db) => Ok(Gain::Manual(db)),
+            Repr::Name(name) => name.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl Serialize for Gain {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Gain::Auto => serializer.serialize_str("auto"),
+            Gain::Manual(db) => serializer.serialize_f32(*db),
+        }
+    }
+}
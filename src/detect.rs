@@ -0,0 +1,443 @@
+use crate::config::{DetectorConfig, FloorMethod};
+use crate::fft::SimpleFft;
+use std::collections::VecDeque;
+use time::{Duration, UtcDateTime};
+
+/// Width of the FFT the detector works on.
+pub const FFT_SIZE: usize = 128;
+
+/// A range of FFT bins which stood out from the noise floor.
+///
+/// Bins count up from the lowest frequency, with the tuned frequency at
+/// `FFT_SIZE / 2`.
+#[derive(Clone, Debug)]
+pub struct Detection {
+    pub start_bin: usize,
+    /// Last bin, inclusive
+    pub end_bin: usize,
+    /// Peak signal to noise ratio across the bins
+    pub snr_db: f32,
+    /// When the signal was first seen
+    pub time: UtcDateTime,
+    /// How long the signal was seen for
+    pub duration: Duration,
+    /// Index of the first sample the signal was seen in, counting from when we started
+    pub sample: u64,
+    /// Number of samples the signal was seen for
+    pub length: u64,
+}
+
+impl Detection {
+    /// Lower and upper edges of the detected bins, in Hz, for a radio tuned to
+    /// `tuned_freq` sampling at `rate`.
+    pub fn frequency_range(&self, tuned_freq: f64, rate: f64) -> (f64, f64) {
+        let bin_width = rate / FFT_SIZE as f64;
+        let edge = |bin: usize| tuned_freq + (bin as f64 - (FFT_SIZE / 2) as f64) * bin_width;
+        (edge(self.start_bin), edge(self.end_bin + 1))
+    }
+
+    fn touches(&self, other: &Detection) -> bool {
+        self.start_bin <= other.end_bin + 1 && other.start_bin <= self.end_bin + 1
+    }
+
+    /// Grow to also cover `other`.
+    fn absorb(&mut self, other: &Detection) {
+        let end = (self.time + self.duration).max(other.time + other.duration);
+        let end_sample = (self.sample + self.length).max(other.sample + other.length);
+        self.start_bin = self.start_bin.min(other.start_bin);
+        self.end_bin = self.end_bin.max(other.end_bin);
+        self.snr_db = self.snr_db.max(other.snr_db);
+        self.time = self.time.min(other.time);
+        self.duration = end - self.time;
+        self.sample = self.sample.min(other.sample);
+        self.length = end_sample - self.sample;
+    }
+}
+
+/// Add `detection` to `all`, combining it with any detection in neighbouring bins.
+pub fn merge(all: &mut Vec<Detection>, detection: Detection) {
+    match all.iter_mut().find(|existing| existing.touches(&detection)) {
+        Some(existing) => existing.absorb(&detection),
+        None => all.push(detection),
+    }
+}
+
+/// Finds bins which stand out from a per-bin noise floor.
+///
+/// Single FFT frames are too noisy to compare bin-by-bin, so `average` frames
+/// are averaged into each spectrum the detector looks at.
+pub struct Detector {
+    fft: SimpleFft,
+    threshold_db: f32,
+    average: usize,
+    /// Sum of the magnitude spectra since the last evaluation, lowest frequency first
+    sum: Box<[f32]>,
+    frames: usize,
+    /// Index of the next sample to be fed in
+    samples: u64,
+    /// Index of the first sample in the spectrum being averaged
+    spectrum_start: u64,
+    /// Per-bin noise floor, once we've seen a spectrum to start it from
+    floor: Option<Box<[f32]>>,
+    model: FloorModel,
+    /// Consecutive spectra each bin has been detected in
+    detected_for: Box<[usize]>,
+    /// Spectra a bin can be detected in before it's learnt into the floor anyway
+    hold_off: usize,
+    frame_duration: Duration,
+    debug: bool,
+}
+
+/// How the noise floor follows the spectra it is fed.
+enum FloorModel {
+    /// Exponential moving average, giving each new spectrum this weight
+    Average { alpha: f32 },
+    /// Minimum over a sliding window, made up of `MINIMUM_SUB_WINDOWS` sub-windows
+    Minimum {
+        /// Spectra in each sub-window
+        sub_window: usize,
+        /// Spectra seen in the current sub-window
+        seen: usize,
+        /// Minimum of the current sub-window so far
+        current: Box<[f32]>,
+        /// Minima of the completed sub-windows, oldest first
+        minima: VecDeque<Box<[f32]>>,
+    },
+}
+
+/// The minimum-statistics window slides in steps of this fraction of its length.
+const MINIMUM_SUB_WINDOWS: usize = 8;
+
+impl Detector {
+    /// Start a detector whose first sample is sample `first_sample`, counting
+    /// from when we started.
+    pub fn new(config: &DetectorConfig, sample_rate: f64, first_sample: u64, debug: bool) -> Self {
+        let frame_duration = FFT_SIZE as f64 / sample_rate;
+        let spectrum_duration = frame_duration * config.average as f64;
+        let spectra_in = |ms: u32| (f64::from(ms) / 1e3 / spectrum_duration).ceil() as usize;
+        let model = match config.floor {
+            FloorMethod::Average => FloorModel::Average {
+                alpha: (1. / spectra_in(config.floor_window_ms) as f64).min(1.) as f32,
+            },
+            FloorMethod::Minimum => FloorModel::Minimum {
+                sub_window: spectra_in(config.floor_window_ms)
+                    .div_ceil(MINIMUM_SUB_WINDOWS)
+                    .max(1),
+                seen: 0,
+                current: vec![f32::INFINITY; FFT_SIZE].into_boxed_slice(),
+                minima: VecDeque::with_capacity(MINIMUM_SUB_WINDOWS),
+            },
+        };
+        Detector {
+            fft: SimpleFft::new(FFT_SIZE),
+            threshold_db: config.threshold_db,
+            average: config.average,
+            sum: vec![0.; FFT_SIZE].into_boxed_slice(),
+            frames: 0,
+            samples: first_sample,
+            spectrum_start: first_sample,
+            floor: None,
+            model,
+            detected_for: vec![0; FFT_SIZE].into_boxed_slice(),
+            hold_off: spectra_in(config.hold_off_ms),
+            frame_duration: Duration::seconds_f64(frame_duration),
+            debug,
+        }
+    }
+
+    /// Carry on from sample `sample` after a gap, such as time spent on other
+    /// frequencies, dropping any partly averaged spectrum.
+    pub fn resume(&mut self, sample: u64) {
+        self.sum.fill(0.);
+        self.frames = 0;
+        self.samples = sample;
+        self.detected_for.fill(0);
+    }
+
+    /// Look through a buffer of cu8 samples, the first of which arrived at `start`.
+    ///
+    /// Returns how many averaged spectra had something in them, and what,
+    /// with detections in neighbouring bins combined.
+    pub fn process(&mut self, buf: &[u8], start: UtcDateTime) -> (usize, Vec<Detection>) {
+        let mut interesting = 0;
+        let mut detections = Vec::new();
+        for (i, chunk) in buf.chunks_exact(2 * self.fft.len).enumerate() {
+            let spectrum = self.fft.process(chunk);
+            if self.frames == 0 {
+                self.spectrum_start = self.samples;
+            }
+            self.samples += self.fft.len as u64;
+            // Reorder from FFT order, which starts at DC, to lowest frequency first
+            for (bin, sum) in self.sum.iter_mut().enumerate() {
+                *sum += spectrum[(bin + FFT_SIZE / 2) % FFT_SIZE];
+            }
+            self.frames += 1;
+            if self.frames < self.average {
+                continue;
+            }
+
+            let first_frame = i as f64 + 1. - self.average as f64;
+            let found = self.evaluate(start + self.frame_duration * first_frame);
+            if !found.is_empty() {
+                interesting += 1;
+            }
+            for detection in found {
+                merge(&mut detections, detection);
+            }
+        }
+        (interesting, detections)
+    }
+
+    /// Compare the averaged spectrum to the floor, then update the floor with it.
+    fn evaluate(&mut self, time: UtcDateTime) -> Vec<Detection> {
+        let frames = self.frames as f32;
+        let mean = self
+            .sum
+            .iter()
+            .map(|sum| sum / frames)
+            .collect::<Box<[f32]>>();
+        self.sum.fill(0.);
+        self.frames = 0;
+
+        let floor = self.floor.get_or_insert_with(|| mean.clone());
+        let snr_db = mean
+            .iter()
+            .zip(floor.iter())
+            .map(|(level, floor)| 20. * (level / floor).log10())
+            .collect::<Vec<f32>>();
+        if self.debug {
+            debug_print(&snr_db, self.threshold_db);
+        }
+
+        let mut found = Vec::new();
+        let mut run: Option<Detection> = None;
+        for (bin, snr_db) in snr_db.iter().copied().enumerate() {
+            if snr_db > self.threshold_db {
+                self.detected_for[bin] += 1;
+                let detection = run.get_or_insert(Detection {
+                    start_bin: bin,
+                    end_bin: bin,
+                    snr_db,
+                    time,
+                    duration: self.frame_duration * self.average as f64,
+                    sample: self.spectrum_start,
+                    length: (self.average * FFT_SIZE) as u64,
+                });
+                detection.end_bin = bin;
+                detection.snr_db = detection.snr_db.max(snr_db);
+            } else {
+                self.detected_for[bin] = 0;
+                found.extend(run.take());
+            }
+        }
+        found.extend(run);
+
+        // Keep signals out of the floor, unless they've been there so long
+        // they're part of the background
+        let learn = self
+            .detected_for
+            .iter()
+            .map(|&spectra| spectra == 0 || spectra > self.hold_off)
+            .collect::<Vec<bool>>();
+        self.model.update(floor, &mean, &learn);
+
+        found
+    }
+}
+
+impl FloorModel {
+    /// Feed in a new spectrum, for the bins in which `learn` is set.
+    fn update(&mut self, floor: &mut [f32], spectrum: &[f32], learn: &[bool]) {
+        match self {
+            FloorModel::Average { alpha } => {
+                for ((floor, level), learn) in floor.iter_mut().zip(spectrum).zip(learn) {
+                    if *learn {
+                        *floor += *alpha * (level - *floor);
+                    }
+                }
+            }
+            FloorModel::Minimum {
+                sub_window,
+                seen,
+                current,
+                minima,
+            } => {
+                for ((minimum, level), learn) in current.iter_mut().zip(spectrum).zip(learn) {
+                    if *learn {
+                        *minimum = minimum.min(*level);
+                    }
+                }
+                *seen += 1;
+
+                for (bin, floor) in floor.iter_mut().enumerate() {
+                    let minimum = minima
+                        .iter()
+                        .map(|minimum| minimum[bin])
+                        .fold(current[bin], f32::min);
+                    // Bins which have been held off for the whole window have nothing to go on
+                    if minimum.is_finite() {
+                        *floor = minimum;
+                    }
+                }
+
+                if *seen == *sub_window {
+                    if minima.len() == MINIMUM_SUB_WINDOWS {
+                        minima.pop_front();
+                    }
+                    minima.push_back(std::mem::replace(
+                        current,
+                        vec![f32::INFINITY; spectrum.len()].into_boxed_slice(),
+                    ));
+                    *seen = 0;
+                }
+            }
+        }
+    }
+}
+
+fn debug_print(snr_db: &[f32], threshold_db: f32) {
+    let spark_chars = " ▁▂▃▄▅▆▇";
+    let max = snr_db.iter().copied().fold(f32::MIN, f32::max);
+    println!(
+        "peak: {:.1} dB, threshold: {:.1} dB, {}",
+        max,
+        threshold_db,
+        snr_db
+            .iter()
+            .map(|v| {
+                let pos = (v / threshold_db * (spark_chars.chars().count() - 1) as f32)
+                    .clamp(0., (spark_chars.chars().count() - 1) as f32)
+                    as usize;
+                if *v > threshold_db {
+                    'X'
+                } else {
+                    spark_chars.chars().nth(pos).unwrap_or(' ')
+                }
+            })
+            .collect::<String>()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample rate which makes each FFT frame 1 ms, and each bin 1 kHz wide.
+    const RATE: f64 = 128_000.;
+
+    /// Bin a tone `TONE_OFFSET` bins above the tuned frequency lands in.
+    const TONE_BIN: usize = FFT_SIZE / 2 + TONE_OFFSET;
+    const TONE_OFFSET: usize = 16;
+
+    /// `ms` of cu8 noise from `seed`, with a tone `TONE_OFFSET` bins up if `tone`.
+    fn samples(ms: usize, tone: bool, seed: &mut u32) -> Vec<u8> {
+        let mut noise = || {
+            // xorshift, so the tests don't depend on luck
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 17;
+            *seed ^= *seed << 5;
+            (*seed % 17) as f64 - 8.
+        };
+        let count = ms * RATE as usize / 1000;
+        let mut data = Vec::with_capacity(2 * count);
+        for i in 0..count {
+            let phase = std::f64::consts::TAU * (TONE_OFFSET * i) as f64 / FFT_SIZE as f64;
+            let amplitude = if tone { 40. } else { 0. };
+            for level in [phase.cos(), phase.sin()] {
+                data.push((128. + amplitude * level + noise()).round() as u8);
+            }
+        }
+        data
+    }
+
+    fn config(floor: FloorMethod, hold_off_ms: u32) -> DetectorConfig {
+        DetectorConfig {
+            threshold_db: 10.,
+            average: 16,
+            floor,
+            floor_window_ms: 100,
+            hold_off_ms,
+            ..DetectorConfig::default()
+        }
+    }
+
+    fn detection(start_bin: usize, end_bin: usize, snr_db: f32, sample: u64) -> Detection {
+        Detection {
+            start_bin,
+            end_bin,
+            snr_db,
+            time: UtcDateTime::UNIX_EPOCH + Duration::milliseconds(sample as i64),
+            duration: Duration::milliseconds(16),
+            sample,
+            length: 16,
+        }
+    }
+
+    #[test]
+    fn finds_a_tone_in_its_bins() {
+        let config = config(FloorMethod::Average, 10_000);
+        let mut detector = Detector::new(&config, RATE, 0, false);
+        let mut seed = 1;
+        let start = UtcDateTime::UNIX_EPOCH;
+        let (_, before) = detector.process(&samples(512, false, &mut seed), start);
+        assert!(before.is_empty(), "{before:?}");
+
+        let (interesting, found) = detector.process(&samples(64, true, &mut seed), start);
+        assert_eq!(interesting, 4);
+        let [detection] = &found[..] else {
+            panic!("expected one detection, not {found:?}");
+        };
+        assert!(detection.start_bin <= TONE_BIN && TONE_BIN <= detection.end_bin);
+        assert!(
+            detection.end_bin - detection.start_bin <= 8,
+            "{detection:?}"
+        );
+        assert!(detection.snr_db > config.threshold_db);
+        assert_eq!(detection.sample, 512 * RATE as u64 / 1000);
+    }
+
+    #[test]
+    fn finds_nothing_in_noise() {
+        let mut detector = Detector::new(&config(FloorMethod::Average, 10_000), RATE, 0, false);
+        let (interesting, found) =
+            detector.process(&samples(1000, false, &mut 1), UtcDateTime::UNIX_EPOCH);
+        assert_eq!(interesting, 0);
+        assert!(found.is_empty(), "{found:?}");
+    }
+
+    /// Whether a tone that carries on for a second is still being detected at
+    /// the end of it.
+    fn still_detected(floor: FloorMethod, hold_off_ms: u32) -> bool {
+        let mut detector = Detector::new(&config(floor, hold_off_ms), RATE, 0, false);
+        let mut seed = 1;
+        let start = UtcDateTime::UNIX_EPOCH;
+        detector.process(&samples(512, false, &mut seed), start);
+        let (_, found) = detector.process(&samples(64, true, &mut seed), start);
+        assert!(!found.is_empty(), "{floor:?} missed the start of the tone");
+        detector.process(&samples(1024, true, &mut seed), start);
+        let (_, found) = detector.process(&samples(256, true, &mut seed), start);
+        !found.is_empty()
+    }
+
+    #[test]
+    fn hold_off_learns_a_carrier_into_the_floor() {
+        for floor in [FloorMethod::Average, FloorMethod::Minimum] {
+            assert!(still_detected(floor, 10_000), "{floor:?} learnt it anyway");
+            assert!(!still_detected(floor, 100), "{floor:?} never learnt it");
+        }
+    }
+
+    #[test]
+    fn merge_joins_neighbouring_bins() {
+        let mut all = Vec::new();
+        merge(&mut all, detection(10, 12, 15., 100));
+        merge(&mut all, detection(20, 21, 12., 100));
+        merge(&mut all, detection(13, 15, 20., 132));
+        assert_eq!(all.len(), 2);
+        assert_eq!((all[0].start_bin, all[0].end_bin), (10, 15));
+        assert_eq!(all[0].snr_db, 20.);
+        assert_eq!((all[0].sample, all[0].length), (100, 48));
+        assert_eq!(all[0].duration, Duration::milliseconds(48));
+        assert_eq!((all[1].start_bin, all[1].end_bin), (20, 21));
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{Context, anyhow, bail};
+use log::debug;
+use rtlsdr_rs::RtlSdr;
+
+/// USB ids of devices known to be rtl2832 based, as recognised by librtlsdr.
+///
+/// `RtlSdr::open` counts through attached devices matching this list, in bus
+/// order, so the position of a device in our own enumeration is its index.
+const KNOWN_DEVICES: &[(u16, u16)] = &[
+    (0x0bda, 0x2832),
+    (0x0bda, 0x2838),
+    (0x0413, 0x6680),
+    (0x0413, 0x6f0f),
+    (0x0458, 0x707f),
+    (0x0ccd, 0x00a9),
+    (0x0ccd, 0x00b3),
+    (0x0ccd, 0x00b4),
+    (0x0ccd, 0x00b5),
+    (0x0ccd, 0x00b7),
+    (0x0ccd, 0x00b8),
+    (0x0ccd, 0x00b9),
+    (0x0ccd, 0x00c0),
+    (0x0ccd, 0x00c6),
+    (0x0ccd, 0x00d3),
+    (0x0ccd, 0x00d7),
+    (0x0ccd, 0x00e0),
+    (0x1554, 0x5020),
+    (0x15f4, 0x0131),
+    (0x15f4, 0x0133),
+    (0x185b, 0x0620),
+    (0x185b, 0x0650),
+    (0x185b, 0x0680),
+    (0x1b80, 0xd393),
+    (0x1b80, 0xd394),
+    (0x1b80, 0xd395),
+    (0x1b80, 0xd397),
+    (0x1b80, 0xd398),
+    (0x1b80, 0xd39d),
+    (0x1b80, 0xd3a4),
+    (0x1b80, 0xd3a8),
+    (0x1b80, 0xd3af),
+    (0x1b80, 0xd3b0),
+    (0x1d19, 0x1101),
+    (0x1d19, 0x1102),
+    (0x1d19, 0x1103),
+    (0x1d19, 0x1104),
+    (0x1f4d, 0xa803),
+    (0x1f4d, 0xb803),
+    (0x1f4d, 0xc803),
+    (0x1f4d, 0xd286),
+    (0x1f4d, 0xd803),
+];
+
+/// An attached rtl-sdr, as seen on the USB bus.
+pub struct DeviceInfo {
+    pub index: usize,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+}
+
+/// List attached rtl-sdr devices, in the order `RtlSdr::open` indexes them.
+///
+/// The strings are left empty if the device can't be opened, e.g. due to
+/// permissions.
+pub fn list() -> anyhow::Result<Vec<DeviceInfo>> {
+    let mut found = Vec::new();
+    for device in rusb::devices().context("enumerating usb devices")?.iter() {
+        let desc = match device.device_descriptor() {
+            Ok(desc) => desc,
+            Err(e) => {
+                debug!("skipping unreadable usb device: {e}");
+                continue;
+            }
+        };
+        if !KNOWN_DEVICES.contains(&(desc.vendor_id(), desc.product_id())) {
+            continue;
+        }
+
+        let mut info = DeviceInfo {
+            index: found.len(),
+            manufacturer: None,
+            product: None,
+            serial: None,
+        };
+        match device.open() {
+            Ok(handle) => {
+                info.manufacturer = handle.read_manufacturer_string_ascii(&desc).ok();
+                info.product = handle.read_product_string_ascii(&desc).ok();
+                info.serial = handle.read_serial_number_string_ascii(&desc).ok();
+            }
+            Err(e) => debug!(
+                "couldn't open device {} to read its strings: {e}",
+                info.index
+            ),
+        }
+        found.push(info);
+    }
+    Ok(found)
+}
+
+/// Find the index of the attached device with this serial number, if it's
+/// attached.
+///
+/// Many dongles leave the factory with the same serial, so it's an error for
+/// more than one to have it; `rtl_eeprom -s` can give them their own.
+pub fn index_of_serial(serial: &str) -> anyhow::Result<Option<usize>> {
+    let matching = list()?
+        .into_iter()
+        .filter(|info| info.serial.as_deref() == Some(serial))
+        .map(|info| info.index)
+        .collect::<Vec<_>>();
+    match matching[..] {
+        [index] => Ok(Some(index)),
+        [] => Ok(None),
+        _ => bail!("devices {matching:?} all have serial {serial:?}, so it can't pick one"),
+    }
+}
+
+/// The gains the tuner of the device at `index` supports, in tenths of a dB.
+/// Only works while nothing else has the device open.
+pub fn tuner_gains(index: usize) -> anyhow::Result<Vec<i32>> {
+    let mut sdr =
+        RtlSdr::open(index).map_err(|e| anyhow!("failed to open device {index}: {e:?}"))?;
+    let gains = sdr
+        .get_tuner_gains()
+        .map_err(|e| anyhow!("failed to list gains of device {index}: {e:?}"));
+    sdr.close()
+        .map_err(|e| anyhow!("failed to close device {index}: {e:?}"))?;
+    gains
+}
@@ -0,0 +1,214 @@
+use crate::config::SampleFormat;
+use crate::fft::generate_blackman_harris_window;
+use num_complex::Complex;
+use std::f64::consts::TAU;
+use std::io::{self, Write};
+
+/// Shift cu8 samples up by a quarter of the sample rate, in place.
+///
+/// This undoes the offset tuning from `optimal_settings`. Multiplying by `j^n`
+/// needs only swaps and negations, and negating an offset-binary byte is just
+/// `255 - x`, so this is exact and cheap. `first_sample` is the index of the
+/// first sample in `data`, so the phase lines up between calls.
+pub fn shift_up_quarter_rate(data: &mut [u8], first_sample: u64) {
+    for (n, sample) in data.chunks_exact_mut(2).enumerate() {
+        let (i, q) = (sample[0], sample[1]);
+        let (i, q) = match (first_sample + n as u64) % 4 {
+            0 => (i, q),
+            1 => (255 - q, i),
+            2 => (255 - i, 255 - q),
+            _ => (q, 255 - i),
+        };
+        sample[0] = i;
+        sample[1] = q;
+    }
+}
+
+/// Digital down-converter: mixes a signal down to DC, low-pass filters it, and
+/// decimates, carrying its state between calls so blocks can be fed in turn.
+pub struct Ddc {
+    taps: Box<[f32]>,
+    decimation: usize,
+    /// NCO phase, in radians
+    phase: f64,
+    /// NCO phase change per input sample, in radians
+    phase_step: f64,
+    /// Mixed samples not yet out of reach of the filter
+    history: Vec<Complex<f32>>,
+    /// Index in `history` of the newest sample the next output is filtered from
+    next: usize,
+}
+
+impl Ddc {
+    /// Move `shift` Hz to DC, keep `bandwidth` Hz around it, and keep one in
+    /// every `decimation` samples, for input at `input_rate`.
+    pub fn new(input_rate: f64, shift: f64, bandwidth: f64, decimation: usize) -> Self {
+        assert!(decimation > 0, "decimation must be at least one");
+        let taps = low_pass(16 * decimation + 1, bandwidth / 2. / input_rate);
+        Ddc {
+            decimation,
+            phase: 0.,
+            phase_step: -TAU * shift / input_rate,
+            // Start with silence, so the first output lines up with the first input
+            history: vec![Complex::new(0., 0.); taps.len() - 1],
+            next: taps.len() - 1,
+            taps,
+        }
+    }
+
+    /// Feed in cu8 samples, appending any completed outputs to `output`.
+    pub fn process(&mut self, input: &[u8], output: &mut Vec<Complex<f32>>) {
+        for pair in input.chunks_exact(2) {
+            let sample = Complex::new(
+                (f32::from(pair[0]) - 127.5) / 127.5,
+                (f32::from(pair[1]) - 127.5) / 127.5,
+            );
+            self.history
+                .push(sample * Complex::from_polar(1., self.phase as f32));
+            self.phase = (self.phase + self.phase_step).rem_euclid(TAU);
+        }
+
+        let len = self.taps.len();
+        while self.next < self.history.len() {
+            let window = &self.history[self.next + 1 - len..=self.next];
+            output.push(
+                window
+                    .iter()
+                    .zip(self.taps.iter())
+                    .map(|(sample, tap)| *sample * *tap)
+                    .sum(),
+            );
+            self.next += self.decimation;
+        }
+
+        let consumed = (self.next + 1 - len).min(self.history.len());
+        self.history.drain(..consumed);
+        self.next -= consumed;
+    }
+}
+
+/// Windowed-sinc low-pass filter with unity gain at DC, cutting off at
+/// `cutoff` times the sample rate.
+fn low_pass(num_taps: usize, cutoff: f64) -> Box<[f32]> {
+    let window = generate_blackman_harris_window(num_taps);
+    let middle = (num_taps - 1) as f64 / 2.;
+    let mut taps = window
+        .iter()
+        .enumerate()
+        .map(|(i, w)| {
+            let x = i as f64 - middle;
+            let sinc = if x == 0. {
+                2. * cutoff
+            } else {
+                (TAU * cutoff * x).sin() / (std::f64::consts::PI * x)
+            };
+            (sinc * f64::from(*w)) as f32
+        })
+        .collect::<Box<[f32]>>();
+    let sum = taps.iter().sum::<f32>();
+    taps.iter_mut().for_each(|tap| *tap /= sum);
+    taps
+}
+
+/// Write samples out little-endian in `format`.
+pub fn write_samples(
+    format: SampleFormat,
+    samples: &[Complex<f32>],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(samples.len() * format.bytes_per_sample());
+    for sample in samples {
+        match format {
+            SampleFormat::Cf32 => {
+                bytes.extend_from_slice(&sample.re.to_le_bytes());
+                bytes.extend_from_slice(&sample.im.to_le_bytes());
+            }
+            SampleFormat::Cs16 => {
+                let scale = |v: f32| (v * 32767.).round().clamp(-32768., 32767.) as i16;
+                bytes.extend_from_slice(&scale(sample.re).to_le_bytes());
+                bytes.extend_from_slice(&scale(sample.im).to_le_bytes());
+            }
+        }
+    }
+    out.write_all(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shift_up_quarter_rate_rotates_each_sample_a_quarter_turn() {
+        let mut data = [200, 100].repeat(5);
+        shift_up_quarter_rate(&mut data, 0);
+        assert_eq!(data, [200, 100, 155, 200, 55, 155, 100, 55, 200, 100]);
+    }
+
+    #[test]
+    fn shift_up_quarter_rate_carries_the_phase_between_calls() {
+        let original = (0..=255).collect::<Vec<u8>>();
+        let mut whole = original.clone();
+        shift_up_quarter_rate(&mut whole, 7);
+
+        let mut parts = original;
+        let (first, second) = parts.split_at_mut(6);
+        shift_up_quarter_rate(first, 7);
+        shift_up_quarter_rate(second, 10);
+        assert_eq!(parts, whole);
+    }
+
+    /// `count` cu8 samples of a tone at `frequency` Hz, sampled at `rate`.
+    fn tone(frequency: f64, rate: f64, count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|n| {
+                let phase = TAU * frequency * n as f64 / rate;
+                [phase.cos(), phase.sin()].map(|v| (127.5 + 100. * v).round() as u8)
+            })
+            .collect()
+    }
+
+    /// Average magnitude of `samples`, skipping the filter's start-up.
+    fn level(samples: &[Complex<f32>]) -> f32 {
+        let settled = &samples[samples.len() / 2..];
+        settled.iter().map(|s| s.norm()).sum::<f32>() / settled.len() as f32
+    }
+
+    #[test]
+    fn ddc_brings_the_shifted_tone_to_dc_and_decimates() {
+        let mut ddc = Ddc::new(48_000., 6_000., 2_000., 4);
+        let mut output = Vec::new();
+        ddc.process(&tone(6_000., 48_000., 4_000), &mut output);
+        assert_eq!(output.len(), 1_000);
+
+        // Full level, and standing still
+        assert!((level(&output) - 100. / 127.5).abs() < 0.02);
+        let settled = &output[output.len() / 2..];
+        for pair in settled.windows(2) {
+            assert!((pair[1] - pair[0]).norm() < 0.01);
+        }
+    }
+
+    #[test]
+    fn ddc_filters_out_what_is_outside_the_band() {
+        let mut ddc = Ddc::new(48_000., 6_000., 2_000., 4);
+        let mut output = Vec::new();
+        ddc.process(&tone(-6_000., 48_000., 4_000), &mut output);
+        // At least 40 dB down
+        assert!(level(&output) < 0.01 * 100. / 127.5);
+    }
+
+    #[test]
+    fn ddc_carries_its_state_between_calls() {
+        let input = tone(5_500., 48_000., 4_001);
+        let mut whole = Ddc::new(48_000., 6_000., 2_000., 4);
+        let mut expected = Vec::new();
+        whole.process(&input, &mut expected);
+
+        let mut parts = Ddc::new(48_000., 6_000., 2_000., 4);
+        let mut output = Vec::new();
+        for chunk in input.chunks(2 * 333) {
+            parts.process(chunk, &mut output);
+        }
+        assert_eq!(output, expected);
+    }
+}
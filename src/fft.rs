@@ -46,7 +46,7 @@ impl SimpleFft {
     }
 }
 
-fn generate_blackman_harris_window(n: usize) -> Box<[f32]> {
+pub(crate) fn generate_blackman_harris_window(n: usize) -> Box<[f32]> {
     let mut window = Vec::with_capacity(n);
     for i in 0..n {
         let x = std::f32::consts::TAU * i as f32 / (n - 1) as f32;
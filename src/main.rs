@@ -1,245 +1,930 @@
+mod calibrate;
+mod config;
+mod detect;
+mod device;
+mod dsp;
 mod fft;
+mod queue;
+mod recording;
+mod rtl_tcp;
+mod server;
+mod sigmf;
+mod source;
+mod writer;
 
-use crate::fft::SimpleFft;
-use log::{LevelFilter, info};
-use rtlsdr_rs::{DEFAULT_BUF_LENGTH, RtlSdr, error::Result};
-use std::collections::VecDeque;
+use crate::config::{Args, Command, Config, DirectSampling, QueueFull};
+use crate::detect::{Detection, Detector};
+use crate::recording::{Block, Recording, Summary};
+use crate::server::Server;
+use crate::source::{Buffer, FileSource, SampleSource, SourceInfo};
+use crate::writer::Writer;
+use anyhow::{Context, anyhow, ensure};
+use clap::Parser;
+use log::{debug, error, info, warn};
+use rtlsdr_rs::DEFAULT_BUF_LENGTH;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::{fs, io, process, thread};
+use std::{process, thread};
 
-const FREQUENCY: u32 = 434_200_000;
-const SAMPLE_RATE: u32 = 2_880_000;
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let mut configs = Config::load(&args).unwrap_or_else(|e| bad_arguments(e));
 
-const DEBUG: bool = false;
-
-// RTL Device Index
-const RTL_INDEX: usize = 0;
-
-fn main() {
-    pretty_env_logger::formatted_builder()
-        .filter_level(LevelFilter::Info)
-        .init();
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger.filter_level(args.log_level);
+    if configs.len() > 1 {
+        // Threads are named after their profiles, to tell the devices apart
+        logger.format(|buf, record| {
+            let thread = thread::current();
+            let name = thread.name().unwrap_or(record.target());
+            writeln!(buf, "{:<5} {name} > {}", record.level(), record.args())
+        });
+    }
+    logger.init();
 
     // Shutdown flag that is set true when ctrl-c signal caught
     static SHUTDOWN: AtomicBool = AtomicBool::new(false);
     ctrlc::set_handler(|| {
         if SHUTDOWN.load(Ordering::Relaxed) {
             info!("Shutdown already requested, exiting immediately.");
-            process::exit(1);
+            // As if killed by the signal, as nothing has been cleaned up
+            process::exit(130);
         }
+        info!("Shutting down once everything received is written; Ctrl-C again to exit now.");
         SHUTDOWN.swap(true, Ordering::Relaxed);
     })
     .unwrap();
 
-    // Get radio and demodulation settings for given frequency and sample rate
-    let radio_config = optimal_settings(FREQUENCY, SAMPLE_RATE);
+    match &args.command {
+        Some(Command::Scan { input }) => {
+            if configs.len() != 1 {
+                bad_arguments(anyhow!("scan takes at most one profile"));
+            }
+            scan(&SHUTDOWN, configs.remove(0), input)
+        }
+        Some(Command::ListDevices) => list_devices(),
+        Some(Command::Calibrate {
+            reference,
+            recording,
+            seconds,
+        }) => {
+            if configs.len() != 1 {
+                bad_arguments(anyhow!("calibrate takes at most one profile"));
+            }
+            calibrate::calibrate(
+                &SHUTDOWN,
+                configs.remove(0),
+                *reference,
+                recording.as_deref(),
+                *seconds,
+            )
+        }
+        None => watch_all(&SHUTDOWN, &configs),
+    }
+}
 
-    // Channel to pass receive data from receiver thread to processor thread
-    let (tx, rx) = mpsc::channel();
+/// Exit as clap does for bad arguments, for those it can't check itself,
+/// including those in the configuration file.
+fn bad_arguments(e: anyhow::Error) -> ! {
+    eprintln!("Error: {e:?}");
+    process::exit(2)
+}
+
+/// Print the attached devices, for picking one by serial, and the gains their
+/// tuners support.
+fn list_devices() -> anyhow::Result<()> {
+    let devices = device::list()?;
+    if devices.is_empty() {
+        println!("No rtl-sdr devices found");
+        return Ok(());
+    }
+    // Not readable without permission to open the device
+    let unknown = || "?".to_string();
+    for device in devices {
+        println!(
+            "{}: {} {}, serial {}",
+            device.index,
+            device.manufacturer.unwrap_or_else(unknown),
+            device.product.unwrap_or_else(unknown),
+            device.serial.unwrap_or_else(unknown)
+        );
+        match device::tuner_gains(device.index) {
+            Ok(gains) => println!("   gains: {} dB", source::format_gains(&gains)),
+            Err(e) => println!("   gains unknown: {e:#}"),
+        }
+    }
+    Ok(())
+}
 
-    // Spawn thread to receive data from Radio
-    let receive_thread = thread::spawn(|| receive(&SHUTDOWN, radio_config, tx));
-    // Spawn thread to process data and output to stdout
-    let process_thread = thread::spawn(|| process(&SHUTDOWN, rx));
+/// Watch each configured input, each on its own threads, until we're asked
+/// to stop. A device failing doesn't stop the others.
+fn watch_all(shutdown: &AtomicBool, configs: &[Config]) -> anyhow::Result<()> {
+    // Before anything's writing, as profiles can share a directory
+    let _locks = configs
+        .iter()
+        .map(|config| &config.output_dir)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|output_dir| writer::lock(output_dir))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if let [config] = configs {
+        return watch(shutdown, config);
+    }
 
-    // Wait for threads to finish
-    process_thread.join().unwrap();
-    receive_thread.join().unwrap();
+    let failed = thread::scope(|s| {
+        let threads = configs
+            .iter()
+            .map(|config| {
+                thread::Builder::new()
+                    .name(config.name.clone())
+                    .spawn_scoped(s, move || {
+                        let result = watch(shutdown, config);
+                        if let Err(e) = &result {
+                            error!("{e:#}");
+                        }
+                        result
+                    })
+                    .expect("failed to spawn thread")
+            })
+            .collect::<Vec<_>>();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(Result::is_err)
+            .count()
+    });
+    ensure!(failed == 0, "{failed} of {} profiles failed", configs.len());
+    Ok(())
 }
 
-/// Thread to open SDR device and send received data to the demod thread until
-/// SHUTDOWN flag is set to true.
-fn receive(
-    shutdown: &AtomicBool,
-    radio_config: RadioConfig,
-    tx: Sender<Box<[u8; DEFAULT_BUF_LENGTH]>>,
-) {
-    // Open device
-    let mut sdr = RtlSdr::open(RTL_INDEX).expect("Failed to open device");
-    // Config receiver
-    config_sdr(
-        &mut sdr,
-        radio_config.capture_freq,
-        radio_config.capture_rate,
-    )
-    .unwrap();
+/// Watch the configured input, writing out events until we're asked to stop.
+fn watch(shutdown: &AtomicBool, config: &Config) -> anyhow::Result<()> {
+    let mut recordings = 0;
+    let result = run(
+        shutdown,
+        config,
+        || source::open(config, shutdown),
+        queue::bounded(config.queue_depth, config.queue_full),
+        |_| recordings += 1,
+    );
+    info!("Wrote {recordings} recordings");
+    result
+}
 
-    info!("Tuned to {} Hz.\n", sdr.get_center_freq());
+/// Run a recording through the detector, writing out events as if it was
+/// coming from the device, then report what was found.
+fn scan(shutdown: &AtomicBool, mut config: Config, input: &Path) -> anyhow::Result<()> {
+    let source = FileSource::open(input, &config)?;
     info!(
-        "Buffer size: {}ms",
-        1000.0 * 0.5 * DEFAULT_BUF_LENGTH as f32 / radio_config.capture_rate as f32
+        "Scanning {} at {} Hz, {} S/s",
+        input.display(),
+        source.frequency,
+        source.sample_rate
     );
-    info!("Sampling at {} S/s", sdr.get_sample_rate());
+    config.frequency = source.frequency;
+    config.sample_rate = source.sample_rate;
+    // The recording is only of the one frequency
+    config.hop = None;
+    let rate = f64::from(source.sample_rate);
+
+    let mut summaries = Vec::new();
+    run(
+        shutdown,
+        &config,
+        || Ok(Box::new(source)),
+        // Only read ahead a little, rather than the whole file into memory,
+        // and never skip any of it
+        queue::bounded(4, QueueFull::Block),
+        |summary| summaries.push(summary),
+    )?;
+
+    println!("{}: {} recordings", input.display(), summaries.len());
+    for summary in &summaries {
+        println!(
+            "  {}: {:.3}s for {:.3}s, {} detections{}{}",
+            summary.name,
+            summary.first_sample as f64 / rate,
+            summary.samples as f64 / rate,
+            summary.annotations,
+            match summary.peak_snr_db {
+                Some(snr_db) => format!(", peak {snr_db:.1} dB"),
+                None => String::new(),
+            },
+            if summary.truncated { ", truncated" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+/// Receive from the source `open` opens on one thread, and process what it
+/// sends through `queue` on this one, until either stops.
+fn run<'s>(
+    shutdown: &AtomicBool,
+    config: &Config,
+    open: impl FnOnce() -> anyhow::Result<Box<dyn SampleSource + 's>> + Send,
+    (tx, rx): (queue::Sender, queue::Receiver),
+    written: impl FnMut(Summary),
+) -> anyhow::Result<()> {
+    let (ready_tx, ready_rx) = mpsc::channel();
+    // Channel for the processor to ask the receiver to hop
+    let (retune_tx, retune_rx) = mpsc::channel();
+    thread::scope(|s| {
+        // Spawn thread to receive data from the source
+        // Named after this thread, to say which device its messages are about
+        let mut receiver = thread::Builder::new();
+        if let Some(name) = thread::current().name() {
+            receiver = receiver.name(name.to_string());
+        }
+        let receive_thread = receiver
+            .spawn_scoped(s, || {
+                receive(shutdown, config, open, ready_tx, tx, retune_rx)
+            })
+            .expect("failed to spawn receiver");
 
-    info!("Reading samples in sync mode...");
-    loop {
+        // The receiver says how the samples are being captured once it has
+        // opened the source; if it couldn't, its error comes from the join
+        let processed = match ready_rx.recv() {
+            Ok(info) => check_ddc(config, info.radio)
+                .and_then(|()| process(config, info, rx, &retune_tx, written)),
+            Err(_) => Ok(()),
+        };
+
+        // Wait for the receiver to notice we've stopped
+        let received = receive_thread.join().unwrap();
+        processed.and(received)
+    })
+}
+
+/// Check the down-converted band fits in what the radio captures.
+fn check_ddc(config: &Config, radio_config: RadioConfig) -> anyhow::Result<()> {
+    if let Some(ddc) = &config.ddc {
+        // How far from the tuned frequency the edge of the down-converted band is
+        let shift = i64::from(ddc.frequency(radio_config.requested_freq()))
+            - i64::from(radio_config.capture_freq);
+        let reach = shift.abs() + i64::from(ddc.bandwidth() / 2);
+        ensure!(
+            reach <= i64::from(radio_config.capture_rate / 2),
+            "ddc band must be inside the {} Hz captured around {} Hz",
+            radio_config.capture_rate,
+            radio_config.capture_freq
+        );
+    }
+    Ok(())
+}
+
+/// Thread to open the source and send received data to the processor thread
+/// until SHUTDOWN flag is set to true, the source runs out, or the processor
+/// stops.
+fn receive<'s>(
+    shutdown: &AtomicBool,
+    config: &Config,
+    open: impl FnOnce() -> anyhow::Result<Box<dyn SampleSource + 's>>,
+    ready: Sender<SourceInfo>,
+    tx: queue::Sender,
+    retunes: Receiver<RadioConfig>,
+) -> anyhow::Result<()> {
+    let mut source = open()?;
+    if ready.send(source.info()).is_err() {
+        return source.close();
+    }
+    let server = match &config.serve {
+        Some(address) => match Server::start(address, source.dongle(), config.client_commands) {
+            Ok(server) => Some(server),
+            Err(e) => {
+                source.close()?;
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+
+    info!("Reading samples...");
+    let result = loop {
         if shutdown.load(Ordering::Relaxed) {
-            break;
+            break Ok(());
         }
-        let mut buf: Box<[u8; DEFAULT_BUF_LENGTH]> = Box::new([0; DEFAULT_BUF_LENGTH]);
-        let n = sdr.read_sync(&mut *buf);
-        if n.is_err() {
-            info!("Read error: {n:#?}");
-            break;
+        for retune in retunes.try_iter() {
+            if let Err(e) = source.retune(retune) {
+                warn!("{e:#}");
+            }
+        }
+        if let Some(server) = &server {
+            for (command, value) in server.commands() {
+                if let Err(e) = source.command(command, value) {
+                    warn!("{e:#}");
+                }
+            }
         }
-        let len = n.unwrap();
-        if len < DEFAULT_BUF_LENGTH {
-            info!("Short read ({len:#?}), samples lost, exiting!");
-            break;
+        let mut buf: Box<[u8; DEFAULT_BUF_LENGTH]> = Box::new([0; DEFAULT_BUF_LENGTH]);
+        let start = match source.read(&mut buf) {
+            Ok(Some(start)) => start,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let len = source.filled();
+        if let Some(server) = &server {
+            server.send(&buf[..len]);
         }
+        // As of this buffer, which can differ from before it was read, e.g.
+        // once a device which wasn't there has been opened, and its gain snapped
+        let SourceInfo { radio, tuning, .. } = source.info();
         // Send received data through the channel to the processor thread
-        tx.send(buf).expect("failed to send");
-    }
-    // Shut down the device and exit
-    info!("Close");
-    sdr.close().unwrap();
+        let gap = source.gap();
+        if gap {
+            info!("Samples were lost; marking the gap in any recording");
+        }
+        if !tx.send(Buffer {
+            start,
+            radio,
+            tuning,
+            gap,
+            overruns: 0,
+            data: buf,
+            len,
+        }) {
+            break Ok(());
+        }
+    };
+    let closed = source.close();
+    result.and(closed)
 }
 
-fn process(shutdown: &AtomicBool, rx: Receiver<Box<[u8; DEFAULT_BUF_LENGTH]>>) {
-    let mut fft = SimpleFft::new(128);
+/// An event in progress.
+struct Event<'c> {
+    /// Index of the first sample of the first detection
+    first_sample: u64,
+    /// Index of the sample after the end of the last detection
+    last_end: u64,
+    /// Blocks with enough detections to count towards the event
+    counting: usize,
+    detections: Vec<Detection>,
+    /// Where the event's recordings are written
+    writer: &'c Writer,
+    /// Where the event is being written, once it has enough counting blocks
+    recording: Option<Recording<'c>>,
+}
 
-    let mut buffer = VecDeque::with_capacity(64);
+/// Look for events in the buffers coming from `rx`, writing each out, and
+/// passing a summary of every recording written to `written`.
+///
+/// Carries on until the receiver stops, which it does when asked to, so
+/// everything it received is processed. Recordings are written out on a
+/// thread of their own, so a slow disk doesn't hold up detection.
+fn process(
+    config: &Config,
+    mut source: SourceInfo,
+    rx: queue::Receiver,
+    retune: &Sender<RadioConfig>,
+    mut written: impl FnMut(Summary),
+) -> anyhow::Result<()> {
+    let mut capture_rate = f64::from(source.radio.capture_rate);
+    let mut next_sample = 0;
+    // Each frequency has its own noise floor, so its own detector
+    let mut detectors: HashMap<(u32, u32), Detector> = HashMap::new();
+    let mut lengths = Lengths::new(config, capture_rate);
+    let mut buffer =
+        VecDeque::with_capacity(lengths.pre_roll.div_ceil(Block::SAMPLES) as usize + 1);
+    let writer = Writer::start();
+    let mut event: Option<Event> = None;
 
-    while !shutdown.load(Ordering::Relaxed) {
-        let buf = rx.recv().unwrap();
-        let mut interesting_in_this_buf = 0;
-        for chunk in buf.chunks_exact(2 * fft.len) {
-            let interestingness = estimate_interestingness(&mut fft, chunk);
-            let interesting = interestingness > 3.;
-            if interesting {
-                interesting_in_this_buf += 1;
-                continue;
+    // Frequencies to hop between, and when to move on from this one
+    let channels = match &config.hop {
+        Some(hop) => hop
+            .frequencies
+            .iter()
+            .map(|&frequency| {
+                optimal_settings(frequency, config.sample_rate, config.direct_sampling)
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut dwell_end = lengths.dwell;
+
+    // Ends once the receiver has stopped, and its queue is empty
+    while let Some(Buffer {
+        start,
+        radio,
+        tuning,
+        gap,
+        overruns,
+        data: buf,
+        len,
+    }) = rx.recv()
+    {
+        // Stop if a recording couldn't be written, rather than losing the rest
+        writer.check()?;
+
+        if radio != source.radio {
+            debug!(
+                "Retuned to {} Hz at {} S/s",
+                radio.capture_freq, radio.capture_rate
+            );
+            // Hops are checked up front, but clients can tune anywhere
+            if let Err(e) = check_ddc(config, radio) {
+                warn!("{e}; recordings won't contain it");
             }
+            // Nothing from before the retune belongs with what comes after
+            finish_event(event.take(), &mut written)?;
+            buffer.clear();
+            source.radio = radio;
+            capture_rate = f64::from(source.radio.capture_rate);
+            lengths = Lengths::new(config, capture_rate);
+            dwell_end = next_sample + lengths.dwell;
+            if let Some(detector) =
+                detectors.get_mut(&(source.radio.capture_freq, source.radio.capture_rate))
+            {
+                detector.resume(next_sample);
+            }
+        }
+        if tuning != source.tuning {
+            debug!("Receiver settings changed to {tuning:?}");
+            // Recordings are labelled with a single gain, and levels from
+            // before the change aren't comparable with those after
+            finish_event(event.take(), &mut written)?;
+            buffer.clear();
+            source.tuning = tuning;
         }
 
-        let gap = 15;
-        let currently_uninteresting = buffer.len() > gap
-            && buffer
-                .iter()
-                .rev()
-                .take(gap)
-                .all(|(interestingness, _)| *interestingness == 0);
+        let detector = detectors
+            .entry((source.radio.capture_freq, source.radio.capture_rate))
+            .or_insert_with(|| {
+                Detector::new(&config.detector, capture_rate, next_sample, config.debug)
+            });
+        let (interesting_in_this_buf, detections) = detector.process(&buf[..len], start);
+        for detection in &detections {
+            let (lower, upper) =
+                detection.frequency_range(f64::from(source.radio.capture_freq), capture_rate);
+            debug!(
+                "Detection at {:.3}-{:.3} MHz, {:.1} dB",
+                lower / 1e6,
+                upper / 1e6,
+                detection.snr_db
+            );
+        }
 
-        if currently_uninteresting {
+        if !detections.is_empty() {
+            let event = event.get_or_insert_with(|| Event {
+                first_sample: detections
+                    .iter()
+                    .map(|d| d.sample)
+                    .min()
+                    .unwrap_or(next_sample),
+                last_end: 0,
+                counting: 0,
+                detections: Vec::new(),
+                writer: &writer,
+                recording: None,
+            });
+            for detection in detections {
+                event.last_end = event.last_end.max(detection.sample + detection.length);
+                detect::merge(&mut event.detections, detection);
+            }
+            if interesting_in_this_buf >= config.detector.min_spectra {
+                event.counting += 1;
+            }
+        }
+
+        let block = Block {
+            sample: next_sample,
+            samples: (len / 2) as u64,
+            start,
+            gap,
+            overruns,
+            data: Arc::from(buf),
+        };
+        next_sample = block.end();
+        buffer.push_back(block);
+
+        if let Some(current) = &mut event {
+            let going = handle_event(config, &source, current, &buffer, &lengths, &mut written)
+                .context("writing recording")?;
+            if !going {
+                event = None;
+            }
+        }
+
+        // Keep enough to provide the pre-roll, or what's needed to start the
+        // recording of an event which hasn't been started yet
+        let keep_from = match &event {
+            Some(event) if event.recording.is_none() => event.first_sample,
+            _ => next_sample,
+        };
+        let keep_from = keep_from.saturating_sub(lengths.pre_roll);
+        while buffer.front().is_some_and(|block| block.end() <= keep_from) {
             buffer.pop_front();
         }
-        buffer.push_back((interesting_in_this_buf, buf));
 
-        let interesting_events = buffer
-            .iter()
-            .filter(|(interestingness, _)| *interestingness > 1)
-            .count();
-
-        if currently_uninteresting && interesting_events > 1 {
-            write_out(buffer.iter().map(|(_, buf)| buf.as_slice()))
-                .expect("writing buffer to file");
-            info!(
-                "Wrote {interesting_events}/{} interesting chunks to file",
-                buffer.len()
-            );
-            buffer.truncate(0);
+        // Move on to the next frequency once we've been on this one long
+        // enough, unless something is happening here
+        if !channels.is_empty() && event.is_none() && next_sample >= dwell_end {
+            let next = channels
+                .iter()
+                .position(|channel| *channel == source.radio)
+                .map_or(0, |i| (i + 1) % channels.len());
+            // The receiver going away is noticed on the next recv()
+            let _ = retune.send(channels[next]);
+            // Try again after another dwell, if the retune doesn't happen
+            dwell_end = next_sample + lengths.dwell;
         }
     }
+
+    // Keep whatever had been written of an event still going when we stopped
+    finish_event(event, &mut written)?;
+    // Wait for all of it to be written out
+    writer.close()
 }
 
-fn write_out<'v>(buffer: impl Iterator<Item = &'v [u8]>) -> io::Result<()> {
-    let now = time::UtcDateTime::now()
-        .format(&time::format_description::well_known::Rfc3339)
-        .expect("well-known format")
-        .replace(':', "_");
+/// The configured pre-roll, post-roll, maximum recording length, and hop
+/// dwell, in samples.
+struct Lengths {
+    pre_roll: u64,
+    post_roll: u64,
+    max_length: u64,
+    dwell: u64,
+}
 
-    let name = format!("snipper_{now}_{FREQUENCY}_{SAMPLE_RATE}.cu8");
-    info!("Writing output to {name}");
-    let mut file = fs::File::create(name)?;
-    for buf in buffer {
-        file.write_all(buf.as_ref())?;
+impl Lengths {
+    fn new(config: &Config, rate: f64) -> Self {
+        let to_samples = |ms: u32| (f64::from(ms) / 1e3 * rate).ceil() as u64;
+        Lengths {
+            pre_roll: to_samples(config.pre_roll_ms),
+            post_roll: to_samples(config.post_roll_ms),
+            max_length: to_samples(config.max_length_ms),
+            dwell: config
+                .hop
+                .as_ref()
+                .map_or(0, |hop| to_samples(hop.dwell_ms)),
+        }
     }
+}
 
-    file.flush()
+/// Finish the recording of an event which has been cut short, if it had one,
+/// marking it as truncated.
+fn finish_event(event: Option<Event>, written: &mut impl FnMut(Summary)) -> anyhow::Result<()> {
+    if let Some(Event {
+        recording: Some(mut recording),
+        detections,
+        ..
+    }) = event
+    {
+        recording.truncate();
+        let summary = recording
+            .finish(&detections, None)
+            .context("writing recording")?;
+        info!("Wrote truncated event to {}", summary.name);
+        written(summary);
+    }
+    Ok(())
 }
 
-fn estimate_interestingness(fft: &mut SimpleFft, chunk: &[u8]) -> f32 {
-    let chunk = fft.process(chunk);
-    let mut sorted = chunk.clone();
-    sorted.sort_unstable_by(f32::total_cmp);
-    assert_eq!(sorted.len(), fft.len);
-    let low_estimate = sorted[sorted.len() * 75 / 100];
-    let high_estimate = sorted[sorted.len() * 95 / 100];
-    if DEBUG {
-        debug_print(&chunk, &sorted);
+/// Move the event on now the newest block is in the buffer, starting, writing
+/// to, splitting, or finishing its recording. Returns whether the event is
+/// still going.
+fn handle_event<'c>(
+    config: &'c Config,
+    source: &SourceInfo,
+    event: &mut Event<'c>,
+    buffer: &VecDeque<Block>,
+    lengths: &Lengths,
+    written: &mut impl FnMut(Summary),
+) -> anyhow::Result<bool> {
+    let newest_end = buffer.back().map_or(0, Block::end);
+    let end = event.last_end + lengths.post_roll;
+    let over = newest_end >= end;
+
+    let mut recording = match event.recording.take() {
+        Some(recording) => recording,
+        None => {
+            if event.counting < config.detector.min_buffers {
+                if over {
+                    debug!("Ignoring event with too few detections");
+                    return Ok(false);
+                }
+                if newest_end - event.first_sample > lengths.max_length {
+                    debug!("Ignoring event that is too long without enough detections");
+                    return Ok(false);
+                }
+                return Ok(true);
+            }
+
+            let Some(first) = buffer.front() else {
+                return Ok(true);
+            };
+            let from = event
+                .first_sample
+                .saturating_sub(lengths.pre_roll)
+                .max(first.sample);
+            // Timed from the block it's in, as there may be gaps before it
+            let block = buffer
+                .iter()
+                .find(|block| from < block.end())
+                .unwrap_or(first);
+            let start_time = block.time_of(from, f64::from(source.radio.capture_rate));
+            Recording::create(config, source, event.writer, from, start_time, None)?
+        }
+    };
+
+    for block in buffer {
+        loop {
+            let limit = end.min(recording.next_sample() + (lengths.max_length - recording.len()));
+            recording.append(block, limit)?;
+            if recording.len() < lengths.max_length || recording.next_sample() >= end {
+                break;
+            }
+            let (summary, next) = recording.split(&event.detections)?;
+            written(summary);
+            recording = next;
+        }
     }
 
-    high_estimate / low_estimate
+    if over {
+        written(recording.finish(&event.detections, None)?);
+        info!("Wrote event of {} counting buffers to file", event.counting);
+        return Ok(false);
+    }
+    event.recording = Some(recording);
+    Ok(true)
 }
 
-fn debug_print(chunk: &[f32], sorted: &[f32]) {
-    let low_estimate = sorted[sorted.len() * 75 / 100];
-    let high_estimate = sorted[sorted.len() * 95 / 100];
-    let ratio = high_estimate / low_estimate;
-    let min = sorted[0];
-    let max = sorted[sorted.len() - 1];
-    let spark_chars = " ▁▂▃▄▅▆▇";
-    let histo = sorted
-        .iter()
-        .step_by(sorted.len() / 10)
-        .map(|v| {
-            let pos = ((v - min) / (max - min) * (spark_chars.len() - 1) as f32).floor() as usize;
-            spark_chars.chars().nth(pos).unwrap_or('X')
-        })
-        .collect::<String>();
-    println!(
-        "median: {:.2} 90%: {:.2}, ratio: {:.2}, {} {}",
-        low_estimate,
-        high_estimate,
-        ratio,
-        histo,
-        chunk
-            .iter()
-            .map(|v| {
-                let pos =
-                    ((v - min) / (max - min) * (spark_chars.len() - 1) as f32).floor() as usize;
-                spark_chars.chars().nth(pos).unwrap_or('X')
-            })
-            .collect::<String>()
-    );
+/// Radio configuration produced by `optimal_settings`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RadioConfig {
+    pub capture_freq: u32,
+    pub capture_rate: u32,
+    /// How far `capture_freq` is above the requested frequency
+    pub offset: u32,
 }
 
-/// Radio configuration produced by `optimal_settings`
-struct RadioConfig {
-    capture_freq: u32,
-    capture_rate: u32,
+impl RadioConfig {
+    /// The frequency that was asked for, which the radio is tuned `offset` above.
+    pub fn requested_freq(&self) -> u32 {
+        self.capture_freq - self.offset
+    }
 }
 
 /// Determine the optimal radio and demodulation configurations for given
 /// frequency and sample rate.
-fn optimal_settings(freq: u32, rate: u32) -> RadioConfig {
+pub fn optimal_settings(freq: u32, rate: u32, direct_sampling: DirectSampling) -> RadioConfig {
     let downsample = (1_000_000 / rate) + 1;
     info!("downsample: {downsample}");
     let capture_rate = downsample * rate;
     info!("rate_in: {rate} capture_rate: {capture_rate}");
-    // Use offset-tuning
-    let capture_freq = freq + capture_rate / 4;
+    // Use offset-tuning, unless there's no tuner to put a DC spike in the way
+    let offset = match direct_sampling {
+        DirectSampling::Off => capture_rate / 4,
+        DirectSampling::I | DirectSampling::Q => 0,
+    };
+    let capture_freq = freq + offset;
     info!("capture_freq: {capture_freq}");
 
     RadioConfig {
         capture_freq,
         capture_rate,
+        offset,
     }
 }
 
-/// Configure the SDR device for a given receive frequency and sample rate.
-fn config_sdr(sdr: &mut RtlSdr, freq: u32, rate: u32) -> Result<()> {
-    // Use auto-gain
-    sdr.set_tuner_gain(rtlsdr_rs::TunerGain::Auto)?;
-    // Disable bias-tee
-    sdr.set_bias_tee(false)?;
-    // Reset the endpoint before we try to read from it (mandatory)
-    sdr.reset_buffer()?;
-    // Set the frequency
-    sdr.set_center_freq(freq)?;
-    // Set sample rate
-    sdr.set_sample_rate(rate)?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DetectorConfig;
+    use crate::detect::FFT_SIZE;
+    use crate::source::Tuning;
+    use std::fs;
+    use std::ops::Range;
+    use std::path::PathBuf;
+    use time::{Duration, UtcDateTime};
+
+    /// Receiver samples in each buffer.
+    const BUFFER: u64 = Block::SAMPLES;
+
+    /// Sample rate which makes each of the detector's spectra exactly 2 ms.
+    const RATE: u32 = 1_024_000;
+
+    /// A tone burst in noise, which ends once `buffers` have been read, or,
+    /// if there's a `shutdown` flag, sets it then.
+    struct Burst<'s> {
+        tone: Range<u64>,
+        buffers: u64,
+        read: u64,
+        shutdown: Option<&'s AtomicBool>,
+        seed: u32,
+    }
+
+    impl SampleSource for Burst<'_> {
+        fn info(&self) -> SourceInfo {
+            SourceInfo {
+                radio: RadioConfig {
+                    capture_freq: 434_000_000,
+                    capture_rate: RATE,
+                    offset: 0,
+                },
+                hardware: "burst".to_string(),
+                tuning: Tuning::default(),
+            }
+        }
+
+        fn read(
+            &mut self,
+            buf: &mut [u8; DEFAULT_BUF_LENGTH],
+        ) -> anyhow::Result<Option<UtcDateTime>> {
+            if self.read == self.buffers {
+                return Ok(None);
+            }
+            let first = self.read * BUFFER;
+            for (i, pair) in buf.chunks_exact_mut(2).enumerate() {
+                let sample = first + i as u64;
+                // A quarter of the way up the band, in the middle of a bin
+                let phase = std::f64::consts::TAU * (sample % 4) as f64 / 4.;
+                let amplitude = if self.tone.contains(&sample) { 40. } else { 0. };
+                for (byte, level) in pair.iter_mut().zip([phase.cos(), phase.sin()]) {
+                    // xorshift, so the tests don't depend on luck
+                    self.seed ^= self.seed << 13;
+                    self.seed ^= self.seed >> 17;
+                    self.seed ^= self.seed << 5;
+                    let noise = (self.seed % 17) as f64 - 8.;
+                    *byte = (128. + amplitude * level + noise).round() as u8;
+                }
+            }
+            self.read += 1;
+            if let Some(shutdown) = self.shutdown
+                && self.read == self.buffers
+            {
+                shutdown.store(true, Ordering::Relaxed);
+                // Samples would carry on coming, if we didn't stop
+                self.buffers += 1;
+            }
+            Ok(Some(
+                UtcDateTime::UNIX_EPOCH + Duration::seconds_f64(first as f64 / f64::from(RATE)),
+            ))
+        }
+    }
+
+    fn config(name: &str) -> Config {
+        let output_dir =
+            std::env::temp_dir().join(format!("rtl-sdr-snipper-{name}-{}", std::process::id()));
+        fs::create_dir_all(&output_dir).unwrap();
+        Config {
+            output_dir,
+            sample_rate: RATE,
+            pre_roll_ms: 10,
+            post_roll_ms: 20,
+            detector: DetectorConfig {
+                floor_window_ms: 100,
+                min_buffers: 1,
+                ..DetectorConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    /// Run `buffers` with a burst of `tone` in them through the snipper,
+    /// shutting down after them if `stop`, returning what it wrote, and the
+    /// metadata and size of the samples of each recording.
+    fn snip(
+        config: &Config,
+        tone: Range<u64>,
+        buffers: u64,
+        stop: bool,
+    ) -> Vec<(Summary, serde_json::Value, u64)> {
+        let shutdown = AtomicBool::new(false);
+        let mut summaries = Vec::new();
+        run(
+            &shutdown,
+            config,
+            || {
+                Ok(Box::new(Burst {
+                    tone,
+                    buffers,
+                    read: 0,
+                    shutdown: stop.then_some(&shutdown),
+                    seed: 1,
+                }))
+            },
+            queue::bounded(config.queue_depth, config.queue_full),
+            |summary| summaries.push(summary),
+        )
+        .unwrap();
+        let written = summaries
+            .into_iter()
+            .map(|summary| {
+                let path = |extension| -> PathBuf {
+                    config
+                        .output_dir
+                        .join(format!("{}.{extension}", summary.name))
+                };
+                let meta = fs::File::open(path(sigmf::META_EXTENSION)).unwrap();
+                let meta = serde_json::from_reader(meta).unwrap();
+                let len = fs::metadata(path(sigmf::DATA_EXTENSION)).unwrap().len();
+                (summary, meta, len)
+            })
+            .collect();
+        fs::remove_dir_all(&config.output_dir).unwrap();
+        written
+    }
+
+    /// The annotations in `meta`, as (start, count).
+    fn annotations(meta: &serde_json::Value) -> Vec<(u64, u64)> {
+        meta["annotations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|annotation| {
+                (
+                    annotation["core:sample_start"].as_u64().unwrap(),
+                    annotation["core:sample_count"].as_u64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    /// 80 ms of tone, from the start of the third buffer.
+    const TONE: Range<u64> = 2 * BUFFER..2 * BUFFER + 81_920;
+
+    #[test]
+    fn run_trims_a_burst_to_the_pre_and_post_roll() {
+        let config = config("trims");
+        let [(summary, meta, len)] = &snip(&config, TONE, 4, false)[..] else {
+            panic!("expected one recording");
+        };
+        // 10 ms of pre-roll, and 20 ms of post-roll
+        assert_eq!(summary.first_sample, TONE.start - 10_240);
+        assert_eq!(summary.samples, 10_240 + 81_920 + 20_480);
+        assert_eq!(*len, 2 * summary.samples);
+        assert!(!summary.truncated);
+        assert_eq!(annotations(meta), [(10_240, 81_920)]);
+        let annotation = &meta["annotations"][0];
+        let bin_width = f64::from(RATE) / FFT_SIZE as f64;
+        let tone = 434_000_000. + f64::from(RATE) / 4.;
+        let lower = annotation["core:freq_lower_edge"].as_f64().unwrap();
+        let upper = annotation["core:freq_upper_edge"].as_f64().unwrap();
+        assert!(lower < tone && tone < upper);
+        assert!(upper - lower <= 9. * bin_width, "{lower}-{upper}");
+        assert!(meta["global"]["snipper:truncated"].is_null());
+    }
+
+    #[test]
+    fn run_splits_long_events_and_links_the_parts() {
+        let config = Config {
+            max_length_ms: 50,
+            ..config("splits")
+        };
+        let written = snip(&config, TONE, 4, false);
+        let samples = written
+            .iter()
+            .map(|(summary, _, len)| {
+                assert_eq!(*len, 2 * summary.samples);
+                (summary.first_sample, summary.samples)
+            })
+            .collect::<Vec<_>>();
+        let first = TONE.start - 10_240;
+        assert_eq!(
+            samples,
+            [
+                (first, 51_200),
+                (first + 51_200, 51_200),
+                (first + 102_400, 10_240)
+            ]
+        );
+        let names = written
+            .iter()
+            .map(|(summary, ..)| summary.name.as_str())
+            .collect::<Vec<_>>();
+        for (i, (_, meta, _)) in written.iter().enumerate() {
+            let global = &meta["global"];
+            let continues = i.checked_sub(1).map(|i| names[i]);
+            assert_eq!(global["snipper:continues"].as_str(), continues);
+            assert_eq!(
+                global["snipper:continued_by"].as_str(),
+                names.get(i + 1).copied()
+            );
+        }
+        let annotations = written
+            .iter()
+            .map(|(_, meta, _)| annotations(meta))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            annotations,
+            [vec![(10_240, 40_960)], vec![(0, 40_960)], vec![]]
+        );
+    }
+
+    #[test]
+    fn run_truncates_an_event_going_on_at_shutdown() {
+        let config = config("truncates");
+        let [(summary, meta, len)] = &snip(&config, TONE.start..u64::MAX, 3, true)[..] else {
+            panic!("expected one recording");
+        };
+        // Everything received is written
+        assert_eq!(summary.first_sample, TONE.start - 10_240);
+        assert_eq!(summary.samples, 10_240 + BUFFER);
+        assert_eq!(*len, 2 * summary.samples);
+        assert!(summary.truncated);
+        assert_eq!(meta["global"]["snipper:truncated"].as_bool(), Some(true));
+        assert_eq!(annotations(meta), [(10_240, BUFFER)]);
+    }
 }
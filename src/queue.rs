@@ -0,0 +1,180 @@
+//! The queue of buffers from the receiver to the processor, which is bounded,
+//! so a processor which falls behind can't use up all the memory.
+
+use crate::config::QueueFull;
+use crate::source::Buffer;
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when a buffer is added, or the sender goes away
+    added: Condvar,
+    /// Signalled when a buffer is taken, or the receiver goes away
+    taken: Condvar,
+    depth: usize,
+    full: QueueFull,
+}
+
+struct State {
+    buffers: VecDeque<Buffer>,
+    /// Whether the other end has gone away
+    closed: bool,
+    /// Buffers dropped so far
+    overruns: u64,
+}
+
+pub struct Sender(Arc<Shared>);
+
+pub struct Receiver(Arc<Shared>);
+
+/// A queue holding up to `depth` buffers, and doing as `full` says with more.
+pub fn bounded(depth: usize, full: QueueFull) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            buffers: VecDeque::with_capacity(depth),
+            closed: false,
+            overruns: 0,
+        }),
+        added: Condvar::new(),
+        taken: Condvar::new(),
+        depth,
+        full,
+    });
+    (Sender(Arc::clone(&shared)), Receiver(shared))
+}
+
+impl Sender {
+    /// Queue a buffer, dropping the oldest or waiting for space if the queue
+    /// is full. Returns false if the receiver has gone away.
+    pub fn send(&self, mut buffer: Buffer) -> bool {
+        let shared = &self.0;
+        let mut state = shared.state.lock().unwrap();
+        while !state.closed && state.buffers.len() >= shared.depth {
+            match shared.full {
+                QueueFull::Block => state = shared.taken.wait(state).unwrap(),
+                QueueFull::DropOldest => {
+                    let dropped = state.buffers.pop_front().expect("queue is full");
+                    state.overruns += 1;
+                    warn!(
+                        "Processing is falling behind, dropped a buffer ({} so far)",
+                        state.overruns
+                    );
+                    // The next buffer no longer follows on from the last one processed
+                    let next = match state.buffers.front_mut() {
+                        Some(next) => next,
+                        None => &mut buffer,
+                    };
+                    next.gap = true;
+                    next.overruns += dropped.overruns + 1;
+                }
+            }
+        }
+        if state.closed {
+            return false;
+        }
+        state.buffers.push_back(buffer);
+        shared.added.notify_one();
+        true
+    }
+}
+
+impl Receiver {
+    /// The oldest buffer, waiting for one if there are none. Returns `None`
+    /// once there are none and the sender has gone away.
+    pub fn recv(&self) -> Option<Buffer> {
+        let shared = &self.0;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if let Some(buffer) = state.buffers.pop_front() {
+                shared.taken.notify_one();
+                return Some(buffer);
+            }
+            if state.closed {
+                return None;
+            }
+            state = shared.added.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().closed = true;
+        self.0.added.notify_all();
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().closed = true;
+        self.0.taken.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RadioConfig;
+    use crate::source::Tuning;
+    use rtlsdr_rs::DEFAULT_BUF_LENGTH;
+    use time::UtcDateTime;
+
+    /// A buffer whose samples are all `n`, so it can be told apart.
+    fn buffer(n: u8) -> Buffer {
+        Buffer {
+            start: UtcDateTime::UNIX_EPOCH,
+            radio: RadioConfig {
+                capture_freq: 434_200_000,
+                capture_rate: 2_880_000,
+                offset: 0,
+            },
+            tuning: Tuning::default(),
+            gap: false,
+            overruns: 0,
+            data: Box::new([n; DEFAULT_BUF_LENGTH]),
+            len: DEFAULT_BUF_LENGTH,
+        }
+    }
+
+    #[test]
+    fn drop_oldest_marks_the_next_buffer() {
+        let (tx, rx) = bounded(2, QueueFull::DropOldest);
+        for n in 0..4 {
+            assert!(tx.send(buffer(n)));
+        }
+        drop(tx);
+
+        // 0 and 1 were dropped, which 2 carries the count of
+        let next = rx.recv().unwrap();
+        assert_eq!(next.data[0], 2);
+        assert!(next.gap);
+        assert_eq!(next.overruns, 2);
+        let next = rx.recv().unwrap();
+        assert_eq!(next.data[0], 3);
+        assert!(!next.gap);
+        assert_eq!(next.overruns, 0);
+        assert!(rx.recv().is_none());
+    }
+
+    #[test]
+    fn drop_oldest_marks_the_new_buffer_when_it_is_the_only_one() {
+        let (tx, rx) = bounded(1, QueueFull::DropOldest);
+        assert!(tx.send(buffer(0)));
+        assert!(tx.send(buffer(1)));
+
+        let next = rx.recv().unwrap();
+        assert_eq!(next.data[0], 1);
+        assert!(next.gap);
+        assert_eq!(next.overruns, 1);
+    }
+
+    #[test]
+    fn send_fails_once_the_receiver_has_gone() {
+        let (tx, rx) = bounded(1, QueueFull::Block);
+        assert!(tx.send(buffer(0)));
+        drop(rx);
+        assert!(!tx.send(buffer(1)));
+    }
+}
@@ -0,0 +1,373 @@
+use crate::config::{Config, SampleFormat, TuningOffset};
+use crate::detect::{self, Detection};
+use crate::dsp::Ddc;
+use crate::source::SourceInfo;
+use crate::writer::{Conversion, Writer};
+use crate::{RadioConfig, sigmf};
+use log::{info, warn};
+use rtlsdr_rs::DEFAULT_BUF_LENGTH;
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, UtcDateTime};
+
+/// A buffer of samples from the receiver.
+pub struct Block {
+    /// Index of the first sample in the buffer, counting from when we started
+    pub sample: u64,
+    /// Samples in the buffer, which is only short of `SAMPLES` at the end of
+    /// a recording being replayed
+    pub samples: u64,
+    /// Time of the first sample in the buffer
+    pub start: UtcDateTime,
+    /// Whether samples were lost just before this buffer
+    pub gap: bool,
+    /// Buffers dropped just before this one, as processing fell behind
+    pub overruns: u64,
+    /// Shared with the writer until it's written out
+    pub data: Arc<[u8; DEFAULT_BUF_LENGTH]>,
+}
+
+impl Block {
+    pub const SAMPLES: u64 = (DEFAULT_BUF_LENGTH / 2) as u64;
+
+    /// Index of the sample after the end of the buffer.
+    pub fn end(&self) -> u64 {
+        self.sample + self.samples
+    }
+
+    /// Time of the sample at index `sample`, for samples arriving at `rate`.
+    pub fn time_of(&self, sample: u64, rate: f64) -> UtcDateTime {
+        self.start + Duration::seconds_f64((sample as f64 - self.sample as f64) / rate)
+    }
+}
+
+/// What went into a finished recording.
+pub struct Summary {
+    /// File name, without an extension
+    pub name: String,
+    /// Index of the first receiver sample in the recording
+    pub first_sample: u64,
+    /// Receiver samples in the recording
+    pub samples: u64,
+    pub annotations: usize,
+    /// Highest signal to noise ratio of the annotations, if there were any
+    pub peak_snr_db: Option<f32>,
+    /// Whether the event was cut short
+    pub truncated: bool,
+}
+
+/// What a recording looks like once shifted or down-converted.
+struct Layout {
+    tuned_freq: f64,
+    /// Centre frequency of the recorded samples
+    centre_freq: f64,
+    sample_rate: f64,
+    /// Width of the band kept in the recording
+    bandwidth: f64,
+    /// Receiver samples per recorded sample
+    decimation: u64,
+    datatype: &'static str,
+    remove_offset: bool,
+    format: Option<SampleFormat>,
+}
+
+impl Layout {
+    fn new(config: &Config, radio_config: RadioConfig) -> Self {
+        let tuned_freq = f64::from(radio_config.capture_freq);
+        let capture_rate = f64::from(radio_config.capture_rate);
+        let remove_offset =
+            config.tuning_offset == TuningOffset::Remove && radio_config.offset != 0;
+        let unchanged = Layout {
+            tuned_freq,
+            centre_freq: tuned_freq,
+            sample_rate: capture_rate,
+            bandwidth: capture_rate,
+            decimation: 1,
+            datatype: "cu8",
+            remove_offset: false,
+            format: None,
+        };
+
+        match &config.ddc {
+            Some(ddc) => {
+                let decimation = (capture_rate / f64::from(ddc.output_rate)).round().max(1.);
+                Layout {
+                    centre_freq: f64::from(ddc.frequency(radio_config.requested_freq())),
+                    sample_rate: capture_rate / decimation,
+                    bandwidth: f64::from(ddc.bandwidth()),
+                    decimation: decimation as u64,
+                    datatype: ddc.format.sigmf_datatype(),
+                    format: Some(ddc.format),
+                    ..unchanged
+                }
+            }
+            None if remove_offset => Layout {
+                centre_freq: tuned_freq - capture_rate / 4.,
+                remove_offset,
+                ..unchanged
+            },
+            None => unchanged,
+        }
+    }
+}
+
+/// A SigMF recording being written, block by block, as an event goes on.
+///
+/// The writing itself is done by `writer`, which this only tells what to write.
+pub struct Recording<'c> {
+    config: &'c Config,
+    /// How the source was set up when the recording started, which it stays
+    /// as, as anything else ends the event
+    source: SourceInfo,
+    writer: &'c Writer,
+    /// What `writer` knows the recording as
+    id: u64,
+    layout: Layout,
+    /// File name, without an extension
+    name: String,
+    /// Index of the first receiver sample in the recording
+    first_sample: u64,
+    /// Index of the next receiver sample to be written
+    next_sample: u64,
+    start_time: UtcDateTime,
+    /// Name of the recording this one carries on from, if it was split
+    continues: Option<String>,
+    /// Receiver samples which came after lost samples, and their times
+    gaps: Vec<(u64, UtcDateTime)>,
+    /// Buffers dropped in the gaps, as processing fell behind
+    overruns: u64,
+    /// Whether the event was cut short, e.g. by shutting down, before it ended
+    truncated: bool,
+}
+
+impl<'c> Recording<'c> {
+    /// Start a recording at receiver sample `first_sample`, which arrived at
+    /// `start_time` from `source` as it is now, carrying on from the recording
+    /// `continues`, if any.
+    pub fn create(
+        config: &'c Config,
+        source: &SourceInfo,
+        writer: &'c Writer,
+        first_sample: u64,
+        start_time: UtcDateTime,
+        continues: Option<&Recording>,
+    ) -> anyhow::Result<Self> {
+        let radio_config = source.radio;
+        let layout = Layout::new(config, radio_config);
+        let time = start_time
+            .format(&Rfc3339)
+            .expect("well-known format")
+            .replace(':', "_");
+        let name = config.output_name(
+            &time,
+            layout.centre_freq.round() as u64,
+            (layout.centre_freq - f64::from(radio_config.requested_freq())).round() as i64,
+            layout.sample_rate.round() as u32,
+        );
+        let path = config
+            .output_dir
+            .join(format!("{name}.{}", sigmf::DATA_EXTENSION));
+        info!("Writing output to {}", path.display());
+
+        let id = match continues {
+            // Keep the filter running, so there's no join in the samples
+            Some(previous) => writer.carry_on(previous.id, path)?,
+            None => {
+                let conversion = match layout.format {
+                    Some(format) => Conversion::DownConvert {
+                        ddc: Ddc::new(
+                            f64::from(radio_config.capture_rate),
+                            layout.centre_freq - layout.tuned_freq,
+                            layout.bandwidth,
+                            layout.decimation as usize,
+                        ),
+                        format,
+                    },
+                    None if layout.remove_offset => Conversion::ShiftUp,
+                    None => Conversion::Raw,
+                };
+                writer.create(path, conversion)?
+            }
+        };
+
+        Ok(Recording {
+            config,
+            source: source.clone(),
+            writer,
+            id,
+            layout,
+            name,
+            first_sample,
+            next_sample: first_sample,
+            start_time,
+            continues: continues.map(|previous| previous.name.clone()),
+            gaps: Vec::new(),
+            overruns: 0,
+            truncated: false,
+        })
+    }
+
+    /// Receiver samples written so far.
+    pub fn len(&self) -> u64 {
+        self.next_sample - self.first_sample
+    }
+
+    /// Index of the next receiver sample to be written.
+    pub fn next_sample(&self) -> u64 {
+        self.next_sample
+    }
+
+    /// Write out the part of `block` we haven't written yet, up to sample `end`.
+    pub fn append(&mut self, block: &Block, end: u64) -> anyhow::Result<()> {
+        let from = self.next_sample.max(block.sample);
+        let to = end.min(block.end());
+        if from >= to {
+            return Ok(());
+        }
+        // Samples were lost before this block, so time jumps forward here
+        if block.gap && from == block.sample {
+            if from == self.first_sample {
+                self.start_time = block.start;
+            } else {
+                info!("Marking gap in {}", self.name);
+                self.gaps.push((from, block.start));
+                self.overruns += block.overruns;
+            }
+        }
+        self.writer.write(
+            self.id,
+            &block.data,
+            2 * (from - block.sample) as usize..2 * (to - block.sample) as usize,
+            from,
+        )?;
+        self.next_sample = to;
+        Ok(())
+    }
+
+    /// Mark the recording as not containing the end of its event.
+    pub fn truncate(&mut self) {
+        self.truncated = true;
+    }
+
+    /// Finish this recording, and carry on in a new one from where it left off.
+    pub fn split(self, detections: &[Detection]) -> anyhow::Result<(Summary, Recording<'c>)> {
+        // Counting from the last time we know, in case of gaps
+        let (sample, time) = self
+            .gaps
+            .last()
+            .copied()
+            .unwrap_or((self.first_sample, self.start_time));
+        let start_time = time
+            + Duration::seconds_f64(
+                (self.next_sample - sample) as f64 / f64::from(self.source.radio.capture_rate),
+            );
+        let next = Recording::create(
+            self.config,
+            &self.source,
+            self.writer,
+            self.next_sample,
+            start_time,
+            Some(&self),
+        )?;
+        let summary = self.finish(detections, Some(&next.name))?;
+        Ok((summary, next))
+    }
+
+    /// Have the samples flushed, and the metadata written, annotated with
+    /// whichever of `detections` fall inside the recording.
+    pub fn finish(
+        mut self,
+        detections: &[Detection],
+        continued_by: Option<&str>,
+    ) -> anyhow::Result<Summary> {
+        let layout = &self.layout;
+        let capture_rate = f64::from(self.source.radio.capture_rate);
+        let range = self.first_sample..self.next_sample;
+        let mut merged = Vec::new();
+        for detection in detections {
+            if detection.sample < range.end && detection.sample + detection.length > range.start {
+                detect::merge(&mut merged, detection.clone());
+            }
+        }
+        let annotations = merged
+            .iter()
+            .filter_map(|detection| {
+                let (lower, upper) = detection.frequency_range(layout.tuned_freq, capture_rate);
+                info!(
+                    "Detected {:.3}-{:.3} MHz at {:.1} dB for {:.1} ms",
+                    lower / 1e6,
+                    upper / 1e6,
+                    detection.snr_db,
+                    detection.duration.as_seconds_f64() * 1e3
+                );
+                // Only keep what made it into the recording's band
+                let lower = lower.max(layout.centre_freq - layout.bandwidth / 2.);
+                let upper = upper.min(layout.centre_freq + layout.bandwidth / 2.);
+                if lower >= upper {
+                    return None;
+                }
+                let from = detection.sample.max(range.start);
+                let to = (detection.sample + detection.length).min(range.end);
+                Some(sigmf::Annotation {
+                    sample_start: (from - range.start) / layout.decimation,
+                    sample_count: (to - from).div_ceil(layout.decimation),
+                    freq_lower_edge: lower,
+                    freq_upper_edge: upper,
+                    label: "burst".to_string(),
+                    snr_db: detection.snr_db,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let summary = Summary {
+            name: self.name.clone(),
+            first_sample: self.first_sample,
+            samples: self.len(),
+            annotations: annotations.len(),
+            peak_snr_db: annotations
+                .iter()
+                .map(|annotation| annotation.snr_db)
+                .reduce(f32::max),
+            truncated: self.truncated,
+        };
+
+        let hw = self.source.hardware.clone();
+        let mut global = sigmf::Global::new(layout.datatype, layout.sample_rate, hw);
+        let tuning = self.source.tuning;
+        global.gain = tuning.gain;
+        global.digital_agc = tuning.digital_agc;
+        global.ppm = tuning.ppm;
+        global.direct_sampling = tuning.direct_sampling;
+        if self.overruns > 0 {
+            warn!(
+                "{} is missing {} buffers dropped as processing fell behind",
+                self.name, self.overruns
+            );
+            global.overruns = Some(self.overruns);
+        }
+        global.truncated = self.truncated;
+        global.continues = self.continues.take();
+        global.continued_by = continued_by.map(str::to_string);
+        // A new capture segment after each gap, saying when it starts
+        let capture = |sample_start, time: UtcDateTime| sigmf::Capture {
+            sample_start,
+            frequency: layout.centre_freq,
+            datetime: time.format(&Rfc3339).expect("well-known format"),
+            tuned_frequency: layout.tuned_freq,
+            offset: layout.centre_freq - f64::from(self.source.radio.requested_freq()),
+        };
+        let mut captures = vec![capture(0, self.start_time)];
+        for &(sample, time) in &self.gaps {
+            captures.push(capture((sample - range.start) / layout.decimation, time));
+        }
+        self.writer.finish(
+            self.id,
+            sigmf::Meta {
+                global,
+                captures,
+                annotations,
+            },
+        )?;
+        Ok(summary)
+    }
+}
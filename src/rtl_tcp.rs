@@ -0,0 +1,620 @@
+//! The rtl_tcp protocol, which serves a device's raw cu8 samples over TCP, and
+//! takes commands to tune it; and a client for it.
+
+use crate::config::{Config, DIRECT_SAMPLING_MAX, DirectSampling, Gain, TUNER_MAX};
+use crate::source::{self, SampleSource, SourceInfo, Tuning};
+use crate::{RadioConfig, optimal_settings};
+use anyhow::{Context, ensure};
+use log::{debug, error, info, warn};
+use rtlsdr_rs::DEFAULT_BUF_LENGTH;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use time::UtcDateTime;
+
+/// What the server sends before any samples.
+const MAGIC: &[u8; 4] = b"RTL0";
+const HEADER_LEN: usize = 12;
+
+/// How long to wait between attempts to reconnect to a server which has gone away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// How long to wait for the server to answer, or send more samples, before
+/// taking it as gone; a server which loses power or network never says so.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sample rates the rtl2832 can do. librtlsdr refuses anything else, and
+/// rtl_tcp ignores the refusal, carrying on at the old rate.
+const SAMPLE_RATES: [RangeInclusive<u32>; 2] = [225_001..=300_000, 900_001..=3_200_000];
+
+/// Tuner names, indexed by the number the server sends in its header.
+const TUNERS: &[&str] = &[
+    "unknown", "E4000", "FC0012", "FC0013", "FC2580", "R820T", "R828D",
+];
+
+/// The number for the R820T in `TUNERS`.
+pub const TUNER_R820T: u32 = 5;
+
+/// Commands the server understands, each sent with a big-endian u32 argument.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Command {
+    Frequency = 0x01,
+    SampleRate = 0x02,
+    /// 0 for automatic gain, 1 for manual
+    GainMode = 0x03,
+    /// Gain in tenths of a dB
+    Gain = 0x04,
+    FreqCorrection = 0x05,
+    IfGain = 0x06,
+    TestMode = 0x07,
+    AgcMode = 0x08,
+    DirectSampling = 0x09,
+    OffsetTuning = 0x0a,
+    RtlXtal = 0x0b,
+    TunerXtal = 0x0c,
+    GainByIndex = 0x0d,
+    BiasTee = 0x0e,
+}
+
+impl Command {
+    pub fn from_u8(code: u8) -> Option<Self> {
+        Some(match code {
+            0x01 => Command::Frequency,
+            0x02 => Command::SampleRate,
+            0x03 => Command::GainMode,
+            0x04 => Command::Gain,
+            0x05 => Command::FreqCorrection,
+            0x06 => Command::IfGain,
+            0x07 => Command::TestMode,
+            0x08 => Command::AgcMode,
+            0x09 => Command::DirectSampling,
+            0x0a => Command::OffsetTuning,
+            0x0b => Command::RtlXtal,
+            0x0c => Command::TunerXtal,
+            0x0d => Command::GainByIndex,
+            0x0e => Command::BiasTee,
+            _ => return None,
+        })
+    }
+
+    /// Check `value` is something the dongle can do, as set up for
+    /// `direct_sampling`, before carrying out a client's command, so we don't
+    /// record samples as something they aren't.
+    pub fn check(self, value: u32, direct_sampling: DirectSampling) -> anyhow::Result<()> {
+        match self {
+            Command::Frequency => {
+                let max = match direct_sampling {
+                    DirectSampling::Off => TUNER_MAX,
+                    DirectSampling::I | DirectSampling::Q => DIRECT_SAMPLING_MAX,
+                };
+                ensure!(
+                    (1..=max).contains(&value),
+                    "can't tune to {value} Hz, only 1 to {max} Hz"
+                );
+            }
+            Command::SampleRate => ensure!(
+                SAMPLE_RATES.iter().any(|rates| rates.contains(&value)),
+                "the rtl2832 can't sample at {value} S/s"
+            ),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+pub fn send_command(stream: &mut impl Write, command: Command, value: u32) -> io::Result<()> {
+    let mut message = [0; 5];
+    message[0] = command as u8;
+    message[1..].copy_from_slice(&value.to_be_bytes());
+    stream.write_all(&message)
+}
+
+/// Throw away whatever has already arrived on `stream`, without waiting for
+/// more, returning how much that was. Whole samples are thrown away, so the
+/// I and Q of those read next stay in step.
+fn drain(stream: &mut BufReader<TcpStream>) -> io::Result<usize> {
+    let mut drained = stream.buffer().len();
+    stream.consume(drained);
+    stream.get_ref().set_nonblocking(true)?;
+    let mut scratch = [0; 16384];
+    let result = loop {
+        match stream.get_mut().read(&mut scratch) {
+            // Disconnected, which the next read finds out
+            Ok(0) => break Ok(()),
+            Ok(len) => drained += len,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e),
+        }
+    };
+    stream.get_ref().set_nonblocking(false)?;
+    result?;
+    if drained % 2 == 1 {
+        stream.read_exact(&mut [0])?;
+        drained += 1;
+    }
+    Ok(drained)
+}
+
+/// What the server says about its dongle when a client connects.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DongleInfo {
+    /// Tuner type; see `TUNERS`
+    pub tuner: u32,
+    /// Number of gain steps the tuner has
+    pub gain_count: u32,
+}
+
+impl DongleInfo {
+    pub fn read(reader: &mut impl Read) -> anyhow::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        ensure!(&header[..4] == MAGIC, "doesn't look like an rtl_tcp server");
+        Ok(DongleInfo {
+            tuner: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            gain_count: u32::from_be_bytes([header[8], header[9], header[10], header[11]]),
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        header[4..8].copy_from_slice(&self.tuner.to_be_bytes());
+        header[8..].copy_from_slice(&self.gain_count.to_be_bytes());
+        writer.write_all(&header)
+    }
+
+    pub fn tuner_name(&self) -> &'static str {
+        TUNERS
+            .get(self.tuner as usize)
+            .copied()
+            .unwrap_or("unknown")
+    }
+}
+
+/// Samples from an rtl_tcp server, which we tune just as we would a local
+/// device, reconnecting whenever the connection is lost.
+pub struct RtlTcpSource<'s> {
+    address: String,
+    radio: RadioConfig,
+    settings: Settings,
+    /// The connection, unless it has been lost and not yet re-established
+    stream: Option<BufReader<TcpStream>>,
+    dongle: DongleInfo,
+    /// Whether the samples waiting to be read are from before a retune
+    stale: bool,
+    /// Whether samples were lost before the last buffer read
+    gap: bool,
+    shutdown: &'s AtomicBool,
+}
+
+/// Everything but the frequency and sample rate we tune the server with.
+#[derive(Copy, Clone, Debug)]
+struct Settings {
+    /// Gain asked for; the server picks the nearest its tuner supports
+    gain: Gain,
+    digital_agc: bool,
+    ppm: i32,
+    /// Whether the bias tee is on, and so needs turning off when we're done
+    bias_tee: bool,
+    direct_sampling: DirectSampling,
+}
+
+impl<'s> RtlTcpSource<'s> {
+    /// Connect to the server at `address`, and tune it as `config` says. Gives
+    /// up on reconnecting once `shutdown` is set.
+    pub fn connect(
+        address: &str,
+        config: &Config,
+        shutdown: &'s AtomicBool,
+    ) -> anyhow::Result<Self> {
+        let radio = optimal_settings(config.frequency, config.sample_rate, config.direct_sampling);
+        let settings = Settings {
+            gain: config.gain,
+            digital_agc: config.digital_agc,
+            ppm: config.ppm,
+            bias_tee: config.bias_tee,
+            direct_sampling: config.direct_sampling,
+        };
+        let (stream, dongle) = connect(address, radio, settings)?;
+        info!(
+            "Connected to rtl_tcp at {address}, with a {} tuner with {} gain steps",
+            dongle.tuner_name(),
+            dongle.gain_count
+        );
+        Ok(RtlTcpSource {
+            address: address.to_string(),
+            radio,
+            settings,
+            stream: Some(stream),
+            dongle,
+            stale: false,
+            gap: false,
+            shutdown,
+        })
+    }
+}
+
+/// Connect to the server at `address`, read its header, and tune it.
+fn connect(
+    address: &str,
+    radio: RadioConfig,
+    settings: Settings,
+) -> anyhow::Result<(BufReader<TcpStream>, DongleInfo)> {
+    let mut stream = Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no addresses to connect to",
+    ));
+    for socket_address in address
+        .to_socket_addrs()
+        .with_context(|| format!("looking up rtl_tcp server {address}"))?
+    {
+        stream = TcpStream::connect_timeout(&socket_address, TIMEOUT);
+        if stream.is_ok() {
+            break;
+        }
+    }
+    let stream = stream.with_context(|| format!("connecting to rtl_tcp at {address}"))?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let dongle = DongleInfo::read(&mut &stream)
+        .with_context(|| format!("reading rtl_tcp header from {address}"))?;
+
+    // The same settings config_sdr() gives a local device
+    let mut commands = &stream;
+    match settings.gain {
+        Gain::Auto => send_command(&mut commands, Command::GainMode, 0)?,
+        Gain::Manual(db) => {
+            send_command(&mut commands, Command::GainMode, 1)?;
+            send_command(&mut commands, Command::Gain, (db * 10.).round() as u32)?;
+        }
+    }
+    send_command(&mut commands, Command::AgcMode, settings.digital_agc.into())?;
+    // Sent as a signed number
+    send_command(&mut commands, Command::FreqCorrection, settings.ppm as u32)?;
+    send_command(
+        &mut commands,
+        Command::DirectSampling,
+        settings.direct_sampling as u32,
+    )?;
+    send_command(&mut commands, Command::Frequency, radio.capture_freq)?;
+    send_command(&mut commands, Command::SampleRate, radio.capture_rate)?;
+    send_command(&mut commands, Command::BiasTee, settings.bias_tee.into())?;
+
+    Ok((BufReader::with_capacity(DEFAULT_BUF_LENGTH, stream), dongle))
+}
+
+impl RtlTcpSource<'_> {
+    fn bias_tee_off(&mut self) -> anyhow::Result<()> {
+        if self.settings.bias_tee {
+            // If we're not connected, the server has to be trusted to turn it
+            // off when we went away
+            if let Some(stream) = &self.stream {
+                send_command(&mut stream.get_ref(), Command::BiasTee, 0)
+                    .with_context(|| format!("turning off bias tee at {}", self.address))?;
+            }
+            self.settings.bias_tee = false;
+            info!("Turned off bias tee");
+        }
+        Ok(())
+    }
+}
+
+/// Don't leave an LNA powered after a panic.
+impl Drop for RtlTcpSource<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.bias_tee_off() {
+            error!("{e:#}");
+        }
+    }
+}
+
+impl SampleSource for RtlTcpSource<'_> {
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            radio: self.radio,
+            hardware: format!(
+                "rtl-sdr ({} tuner), via rtl_tcp at {}",
+                self.dongle.tuner_name(),
+                self.address
+            ),
+            tuning: Tuning {
+                gain: Some(self.settings.gain),
+                digital_agc: Some(self.settings.digital_agc),
+                ppm: Some(self.settings.ppm),
+                direct_sampling: Some(self.settings.direct_sampling),
+            },
+        }
+    }
+
+    fn dongle(&self) -> DongleInfo {
+        self.dongle
+    }
+
+    fn command(&mut self, command: Command, value: u32) -> anyhow::Result<()> {
+        command.check(value, self.settings.direct_sampling)?;
+        match command {
+            Command::Frequency => {
+                // The client wants the frequency in the middle, so there's no offset
+                self.radio.capture_freq = value;
+                self.radio.offset = 0;
+            }
+            Command::SampleRate => {
+                // The offset was a quarter of the old rate, so no longer lines up
+                self.radio.capture_rate = value;
+                self.radio.offset = 0;
+            }
+            Command::GainMode if value == 0 => self.settings.gain = Gain::Auto,
+            Command::Gain => self.settings.gain = Gain::Manual(value as f32 / 10.),
+            Command::AgcMode => self.settings.digital_agc = value != 0,
+            Command::FreqCorrection => self.settings.ppm = value as i32,
+            _ => {
+                debug!("Ignoring {command:?}");
+                return Ok(());
+            }
+        }
+        // Passed on now if we're connected, or sent with everything else on reconnecting
+        if let Some(stream) = &self.stream {
+            if command == Command::Gain {
+                send_command(&mut stream.get_ref(), Command::GainMode, 1)?;
+            }
+            send_command(&mut stream.get_ref(), command, value)?;
+            self.stale = true;
+        }
+        Ok(())
+    }
+
+    fn retune(&mut self, radio: RadioConfig) -> anyhow::Result<()> {
+        // Sent with everything else on reconnecting, if we're not connected
+        if let Some(stream) = &self.stream {
+            send_command(
+                &mut stream.get_ref(),
+                Command::Frequency,
+                radio.capture_freq,
+            )?;
+            if radio.capture_rate != self.radio.capture_rate {
+                send_command(
+                    &mut stream.get_ref(),
+                    Command::SampleRate,
+                    radio.capture_rate,
+                )?;
+            }
+            self.stale = true;
+        }
+        self.radio = radio;
+        Ok(())
+    }
+
+    fn gap(&self) -> bool {
+        self.gap
+    }
+
+    fn close(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.bias_tee_off()
+    }
+
+    fn read(&mut self, buf: &mut [u8; DEFAULT_BUF_LENGTH]) -> anyhow::Result<Option<UtcDateTime>> {
+        self.gap = false;
+        loop {
+            if let Some(stream) = &mut self.stream {
+                let read = if self.stale {
+                    // Everything already here is from before the retune, as is
+                    // about a buffer more the server had on its way
+                    drain(stream).and_then(|drained| {
+                        debug!("Discarded {drained} bytes from before retuning");
+                        source::fill(stream, buf)
+                    })
+                } else {
+                    source::fill(stream, buf)
+                };
+                match read {
+                    Ok(DEFAULT_BUF_LENGTH) if self.stale => {
+                        self.stale = false;
+                        continue;
+                    }
+                    Ok(DEFAULT_BUF_LENGTH) => {
+                        return Ok(Some(source::received_now(self.radio.capture_rate)));
+                    }
+                    Ok(_) => warn!("rtl_tcp server at {} disconnected", self.address),
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        warn!("rtl_tcp server at {} stopped sending samples", self.address);
+                    }
+                    Err(e) => warn!("reading from rtl_tcp server at {}: {e}", self.address),
+                }
+                self.stream = None;
+            }
+
+            if self.shutdown.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            thread::sleep(RECONNECT_DELAY);
+            match connect(&self.address, self.radio, self.settings) {
+                Ok((stream, dongle)) => {
+                    info!("Reconnected to rtl_tcp at {}", self.address);
+                    self.stream = Some(stream);
+                    self.dongle = dongle;
+                    self.gap = true;
+                }
+                Err(e) => warn!("{e:#}, retrying"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Accept a client as a stand-in server for an R820T with 29 gain steps,
+    /// and read the first `count` commands it sends.
+    fn accept(listener: &TcpListener, count: usize) -> (TcpStream, Vec<(u8, u32)>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        DongleInfo {
+            tuner: TUNER_R820T,
+            gain_count: 29,
+        }
+        .write(&mut stream)
+        .unwrap();
+        let commands = (0..count)
+            .map(|_| {
+                let mut message = [0; 5];
+                stream.read_exact(&mut message).unwrap();
+                let value = u32::from_be_bytes([message[1], message[2], message[3], message[4]]);
+                (message[0], value)
+            })
+            .collect();
+        (stream, commands)
+    }
+
+    #[test]
+    fn connect_tunes_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || accept(&listener, 8).1);
+
+        let radio = RadioConfig {
+            capture_freq: 434_920_000,
+            capture_rate: 2_880_000,
+            offset: 720_000,
+        };
+        let settings = Settings {
+            gain: Gain::Manual(29.7),
+            digital_agc: true,
+            ppm: -12,
+            bias_tee: true,
+            direct_sampling: DirectSampling::Off,
+        };
+        let (_stream, dongle) = connect(&address, radio, settings).unwrap();
+        assert_eq!(dongle.tuner_name(), "R820T");
+        assert_eq!(dongle.gain_count, 29);
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                (Command::GainMode as u8, 1),
+                (Command::Gain as u8, 297),
+                (Command::AgcMode as u8, 1),
+                (Command::FreqCorrection as u8, -12i32 as u32),
+                (Command::DirectSampling as u8, 0),
+                (Command::Frequency as u8, 434_920_000),
+                (Command::SampleRate as u8, 2_880_000),
+                (Command::BiasTee as u8, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn dongle_info_is_parsed() {
+        let header = [b'R', b'T', b'L', b'0', 0, 0, 0, 6, 0, 0, 0, 29];
+        let dongle = DongleInfo::read(&mut header.as_slice()).unwrap();
+        assert_eq!(dongle.tuner, 6);
+        assert_eq!(dongle.tuner_name(), "R828D");
+        assert_eq!(dongle.gain_count, 29);
+
+        let mut written = Vec::new();
+        dongle.write(&mut written).unwrap();
+        assert_eq!(written, header);
+    }
+
+    #[test]
+    fn check_refuses_what_the_dongle_cant_do() {
+        let check = |command: Command, value, direct_sampling| {
+            command.check(value, direct_sampling).is_ok()
+        };
+        assert!(check(Command::Frequency, 434_920_000, DirectSampling::Off));
+        assert!(!check(Command::Frequency, 0, DirectSampling::Off));
+        assert!(!check(
+            Command::Frequency,
+            3_000_000_000,
+            DirectSampling::Off
+        ));
+        assert!(check(Command::Frequency, 7_100_000, DirectSampling::Q));
+        assert!(!check(Command::Frequency, 100_000_000, DirectSampling::Q));
+        assert!(check(Command::SampleRate, 2_880_000, DirectSampling::Off));
+        assert!(!check(Command::SampleRate, 500_000, DirectSampling::Off));
+    }
+
+    #[test]
+    fn dongle_info_rejects_bad_magic() {
+        let header = *b"HTTP/1.1 200";
+        assert!(DongleInfo::read(&mut header.as_slice()).is_err());
+    }
+
+    #[test]
+    fn read_reconnects_and_marks_gap() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let samples = vec![128; DEFAULT_BUF_LENGTH];
+            // One buffer, then the connection drops
+            let (mut stream, _) = accept(&listener, 7);
+            stream.write_all(&samples).unwrap();
+            drop(stream);
+            let (mut stream, commands) = accept(&listener, 7);
+            stream.write_all(&samples).unwrap();
+            commands
+        });
+
+        let shutdown = AtomicBool::new(false);
+        let config = Config::default();
+        let mut source = RtlTcpSource::connect(&address, &config, &shutdown).unwrap();
+        let mut buf = Box::new([0; DEFAULT_BUF_LENGTH]);
+        assert!(source.read(&mut buf).unwrap().is_some());
+        assert!(!source.gap());
+        assert!(source.read(&mut buf).unwrap().is_some());
+        assert!(source.gap());
+
+        // Tuned again just as before
+        let commands = server.join().unwrap();
+        assert_eq!(
+            commands[4],
+            (Command::Frequency as u8, source.info().radio.capture_freq)
+        );
+    }
+
+    #[test]
+    fn read_discards_samples_from_before_a_retune() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = accept(&listener, 7);
+            // A buffer, and some of the next, before the retune
+            let mut samples = vec![1; DEFAULT_BUF_LENGTH + 1000];
+            stream.write_all(&samples).unwrap();
+            let mut message = [0; 5];
+            stream.read_exact(&mut message).unwrap();
+            assert_eq!(message[0], Command::Frequency as u8);
+            // The buffer still on its way, then the retuned samples
+            thread::sleep(Duration::from_millis(200));
+            samples.fill(2);
+            stream.write_all(&samples[..DEFAULT_BUF_LENGTH]).unwrap();
+            samples.fill(3);
+            stream.write_all(&samples[..DEFAULT_BUF_LENGTH]).unwrap();
+            stream
+        });
+
+        let shutdown = AtomicBool::new(false);
+        let config = Config::default();
+        let mut source = RtlTcpSource::connect(&address, &config, &shutdown).unwrap();
+        let mut buf = Box::new([0; DEFAULT_BUF_LENGTH]);
+        assert!(source.read(&mut buf).unwrap().is_some());
+        assert!(buf.iter().all(|&b| b == 1));
+
+        let mut radio = source.info().radio;
+        radio.capture_freq += 1_000_000;
+        source.retune(radio).unwrap();
+        assert!(source.read(&mut buf).unwrap().is_some());
+        assert!(buf.iter().all(|&b| b == 3));
+        drop(server.join().unwrap());
+    }
+}
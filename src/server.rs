@@ -0,0 +1,198 @@
+//! An rtl_tcp server, sharing the samples we receive with clients like SDR# or
+//! GQRX while we carry on watching them.
+
+use crate::config::ClientCommands;
+use crate::rtl_tcp::{Command, DongleInfo};
+use anyhow::Context;
+use log::{debug, info, warn};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Buffers queued for each client; clients which fall further behind miss buffers.
+const CLIENT_QUEUE: usize = 8;
+
+pub struct Server {
+    clients: Arc<Mutex<Vec<Client>>>,
+    /// Commands from clients, if they're being honoured
+    commands: Receiver<(Command, u32)>,
+}
+
+struct Client {
+    address: SocketAddr,
+    samples: SyncSender<Arc<[u8]>>,
+}
+
+impl Server {
+    /// Listen on `address`, telling clients they're talking to `dongle`.
+    pub fn start(
+        address: &str,
+        dongle: DongleInfo,
+        policy: ClientCommands,
+    ) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(address)
+            .with_context(|| format!("listening for rtl_tcp clients on {address}"))?;
+        info!("Serving rtl_tcp on {}", listener.local_addr()?);
+
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let (commands_tx, commands) = mpsc::channel();
+        let accepting = Arc::clone(&clients);
+        // Runs until the process exits
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let accepted =
+                    stream.and_then(|stream| accept(stream, dongle, policy, commands_tx.clone()));
+                match accepted {
+                    Ok(client) => accepting.lock().unwrap().push(client),
+                    Err(e) => warn!("accepting rtl_tcp client: {e}"),
+                }
+            }
+        });
+
+        Ok(Server { clients, commands })
+    }
+
+    /// Send a buffer of samples to every client, forgetting any which have gone away.
+    pub fn send(&self, data: &[u8]) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return;
+        }
+        let data = Arc::<[u8]>::from(data);
+        clients.retain(|client| match client.samples.try_send(Arc::clone(&data)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!("rtl_tcp client {} is falling behind", client.address);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                info!("rtl_tcp client {} disconnected", client.address);
+                false
+            }
+        });
+    }
+
+    /// Commands from clients to be carried out, oldest first.
+    pub fn commands(&self) -> impl Iterator<Item = (Command, u32)> + '_ {
+        self.commands.try_iter()
+    }
+}
+
+/// Send the new client the header, and start threads to send it samples and
+/// listen for its commands.
+fn accept(
+    stream: TcpStream,
+    dongle: DongleInfo,
+    policy: ClientCommands,
+    commands: Sender<(Command, u32)>,
+) -> std::io::Result<Client> {
+    let address = stream.peer_addr()?;
+    info!("rtl_tcp client {address} connected");
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    dongle.write(&mut writer)?;
+
+    let (samples, queue) = mpsc::sync_channel::<Arc<[u8]>>(CLIENT_QUEUE);
+    thread::spawn(move || {
+        for data in queue {
+            if writer.write_all(&data).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut reader = stream;
+    thread::spawn(move || {
+        let mut message = [0; 5];
+        while reader.read_exact(&mut message).is_ok() {
+            let value = u32::from_be_bytes([message[1], message[2], message[3], message[4]]);
+            let Some(command) = Command::from_u8(message[0]) else {
+                debug!("Unknown rtl_tcp command {:#04x} from {address}", message[0]);
+                continue;
+            };
+            match policy {
+                ClientCommands::Ignore => debug!("Ignoring {command:?} {value} from {address}"),
+                ClientCommands::Honour => {
+                    info!("{command:?} {value} from {address}");
+                    if commands.send((command, value)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(Client { address, samples })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtl_tcp::{self, TUNER_R820T};
+    use std::time::{Duration, Instant};
+
+    const DONGLE: DongleInfo = DongleInfo {
+        tuner: TUNER_R820T,
+        gain_count: 29,
+    };
+
+    /// Start a server on a free port, and connect `count` clients to it,
+    /// returning them once the server has taken them on.
+    fn start(policy: ClientCommands, count: usize) -> (Server, Vec<TcpStream>) {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let address = format!("127.0.0.1:{port}");
+        let server = Server::start(&address, DONGLE, policy).unwrap();
+        let clients = (0..count)
+            .map(|_| TcpStream::connect(&address).unwrap())
+            .collect();
+        wait_for(|| server.clients.lock().unwrap().len() == count);
+        (server, clients)
+    }
+
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn clients_get_the_header_then_every_buffer() {
+        let (server, clients) = start(ClientCommands::Ignore, 2);
+        server.send(&[1; 100]);
+        server.send(&[2; 100]);
+        for mut client in clients {
+            let dongle = DongleInfo::read(&mut client).unwrap();
+            assert_eq!(dongle.tuner, DONGLE.tuner);
+            assert_eq!(dongle.gain_count, DONGLE.gain_count);
+            let mut samples = [0; 200];
+            client.read_exact(&mut samples).unwrap();
+            assert!(samples[..100].iter().all(|&b| b == 1));
+            assert!(samples[100..].iter().all(|&b| b == 2));
+        }
+    }
+
+    #[test]
+    fn commands_are_ignored_unless_honoured() {
+        let (server, mut clients) = start(ClientCommands::Ignore, 1);
+        rtl_tcp::send_command(&mut clients[0], Command::Frequency, 100_000_000).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(server.commands().next().is_none());
+
+        let (server, mut clients) = start(ClientCommands::Honour, 1);
+        rtl_tcp::send_command(&mut clients[0], Command::Frequency, 100_000_000).unwrap();
+        let mut received = None;
+        wait_for(|| {
+            received = server.commands().next();
+            received.is_some()
+        });
+        assert_eq!(received, Some((Command::Frequency, 100_000_000)));
+    }
+}
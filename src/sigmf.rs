@@ -0,0 +1,189 @@
+//! Just enough of SigMF, <https://sigmf.org/>, to describe our recordings.
+
+use crate::config::{DirectSampling, Gain};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub const DATA_EXTENSION: &str = "sigmf-data";
+pub const META_EXTENSION: &str = "sigmf-meta";
+
+/// Namespace for the fields SigMF has no core equivalent for.
+const EXTENSION: &str = "snipper";
+
+/// Contents of a `.sigmf-meta` file.
+#[derive(Serialize)]
+pub struct Meta {
+    pub global: Global,
+    pub captures: Vec<Capture>,
+    pub annotations: Vec<Annotation>,
+}
+
+#[derive(Serialize)]
+pub struct Global {
+    #[serde(rename = "core:datatype")]
+    pub datatype: &'static str,
+    #[serde(rename = "core:sample_rate")]
+    pub sample_rate: f64,
+    #[serde(rename = "core:version")]
+    pub version: &'static str,
+    #[serde(rename = "core:hw")]
+    pub hw: String,
+    #[serde(rename = "core:recorder")]
+    pub recorder: &'static str,
+    #[serde(rename = "core:extensions")]
+    pub extensions: [Extension; 1],
+    /// Tuner gain in effect in dB, or "auto", if known
+    #[serde(rename = "snipper:gain", skip_serializing_if = "Option::is_none")]
+    pub gain: Option<Gain>,
+    /// Whether the rtl2832's digital AGC was on, if known
+    #[serde(
+        rename = "snipper:digital_agc",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub digital_agc: Option<bool>,
+    /// Frequency correction applied to the tuner, in ppm, if known
+    #[serde(rename = "snipper:ppm", skip_serializing_if = "Option::is_none")]
+    pub ppm: Option<i32>,
+    /// Which ADC input was sampled directly, bypassing the tuner, if known
+    #[serde(
+        rename = "snipper:direct_sampling",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub direct_sampling: Option<DirectSampling>,
+    /// Receiver buffers dropped from the gaps in the recording, as processing
+    /// fell behind, if any were
+    #[serde(rename = "snipper:overruns", skip_serializing_if = "Option::is_none")]
+    pub overruns: Option<u64>,
+    /// Whether the event was cut short, e.g. by shutting down, before it ended
+    #[serde(rename = "snipper:truncated", skip_serializing_if = "is_false")]
+    pub truncated: bool,
+    /// Name of the recording this one carries on from, when an event was too
+    /// long to fit in one
+    #[serde(rename = "snipper:continues", skip_serializing_if = "Option::is_none")]
+    pub continues: Option<String>,
+    /// Name of the recording carrying on from this one
+    #[serde(
+        rename = "snipper:continued_by",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub continued_by: Option<String>,
+}
+
+impl Global {
+    /// Defaults for everything but the details of this recording.
+    pub fn new(datatype: &'static str, sample_rate: f64, hw: String) -> Self {
+        Global {
+            datatype,
+            sample_rate,
+            version: "1.0.0",
+            hw,
+            recorder: concat!("rtl-sdr-snipper ", env!("CARGO_PKG_VERSION")),
+            extensions: [Extension {
+                name: EXTENSION,
+                version: env!("CARGO_PKG_VERSION"),
+                optional: true,
+            }],
+            gain: None,
+            digital_agc: None,
+            ppm: None,
+            direct_sampling: None,
+            overruns: None,
+            truncated: false,
+            continues: None,
+            continued_by: None,
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Serialize)]
+pub struct Extension {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub optional: bool,
+}
+
+#[derive(Serialize)]
+pub struct Capture {
+    #[serde(rename = "core:sample_start")]
+    pub sample_start: u64,
+    /// Centre frequency of the samples, in Hz
+    #[serde(rename = "core:frequency")]
+    pub frequency: f64,
+    /// ISO-8601 time of the first sample
+    #[serde(rename = "core:datetime")]
+    pub datetime: String,
+    /// Frequency the radio was actually tuned to, in Hz
+    #[serde(rename = "snipper:tuned_frequency")]
+    pub tuned_frequency: f64,
+    /// How far `frequency` is above the frequency that was asked for, in Hz
+    #[serde(rename = "snipper:offset")]
+    pub offset: f64,
+}
+
+#[derive(Serialize)]
+pub struct Annotation {
+    #[serde(rename = "core:sample_start")]
+    pub sample_start: u64,
+    #[serde(rename = "core:sample_count")]
+    pub sample_count: u64,
+    #[serde(rename = "core:freq_lower_edge")]
+    pub freq_lower_edge: f64,
+    #[serde(rename = "core:freq_upper_edge")]
+    pub freq_upper_edge: f64,
+    #[serde(rename = "core:label")]
+    pub label: String,
+    /// Peak signal to noise ratio of the detection
+    #[serde(rename = "snipper:snr_db")]
+    pub snr_db: f32,
+}
+
+impl Meta {
+    /// Write the metadata to `path`, and sync it to disk.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(fs::File::create(path)?);
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.flush()?;
+        file.get_ref().sync_all()
+    }
+}
+
+/// The parts of a `.sigmf-meta` file we need to read its samples back.
+#[derive(Deserialize)]
+pub struct Source {
+    pub global: SourceGlobal,
+    #[serde(default)]
+    pub captures: Vec<SourceCapture>,
+}
+
+#[derive(Deserialize)]
+pub struct SourceGlobal {
+    #[serde(rename = "core:datatype")]
+    pub datatype: String,
+    #[serde(rename = "core:sample_rate")]
+    pub sample_rate: f64,
+}
+
+#[derive(Deserialize)]
+pub struct SourceCapture {
+    #[serde(rename = "core:frequency")]
+    pub frequency: Option<f64>,
+    #[serde(rename = "core:datetime")]
+    pub datetime: Option<String>,
+}
+
+impl Source {
+    pub fn read(meta_path: &Path) -> anyhow::Result<Self> {
+        let file = fs::File::open(meta_path)
+            .with_context(|| format!("opening {}", meta_path.display()))?;
+        serde_json::from_reader(io::BufReader::new(file))
+            .with_context(|| format!("parsing {}", meta_path.display()))
+    }
+}
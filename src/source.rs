@@ -0,0 +1,905 @@
+//! Where samples come from: a device, a recording, or another program.
+
+use crate::config::{Config, DirectSampling, Gain, Input};
+use crate::rtl_tcp::{Command, DongleInfo, RtlTcpSource, TUNER_R820T};
+use crate::{RadioConfig, device, optimal_settings, sigmf};
+use anyhow::{Context, anyhow, bail, ensure};
+use log::{debug, error, info, warn};
+use rtlsdr_rs::{DEFAULT_BUF_LENGTH, DirectSampleMode, RtlSdr, TunerGain};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Instant;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, UtcDateTime};
+
+/// How long to wait between attempts to reopen a device which has gone away.
+const REOPEN_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// How often to say we're still waiting for a device.
+const ABSENT_WARNING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// A buffer of cu8 samples, as passed from the receiver to the processor.
+pub struct Buffer {
+    /// Time of the first sample in the buffer
+    pub start: UtcDateTime,
+    /// How the samples were captured, which changes if the source is retuned
+    pub radio: RadioConfig,
+    /// How the receiver was set up, which changes if an rtl_tcp client says so
+    pub tuning: Tuning,
+    /// Whether samples were lost just before this buffer, so it doesn't
+    /// follow on from the last
+    pub gap: bool,
+    /// Buffers dropped just before this one, as processing fell behind
+    pub overruns: u64,
+    pub data: Box<[u8; DEFAULT_BUF_LENGTH]>,
+    /// Bytes of `data` holding samples, which is all of it but at the end of
+    /// a recording being replayed
+    pub len: usize,
+}
+
+/// How the samples coming from a source were captured.
+#[derive(Clone)]
+pub struct SourceInfo {
+    pub radio: RadioConfig,
+    /// Description of the receiver, for the recordings' metadata
+    pub hardware: String,
+    pub tuning: Tuning,
+}
+
+/// How the receiver is set up, apart from its frequency and sample rate, as
+/// far as is known.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Tuning {
+    /// Tuner gain in effect
+    pub gain: Option<Gain>,
+    /// Whether the rtl2832's digital AGC is on
+    pub digital_agc: Option<bool>,
+    /// Frequency correction applied to the tuner, in ppm
+    pub ppm: Option<i32>,
+    /// Whether the tuner was bypassed
+    pub direct_sampling: Option<DirectSampling>,
+}
+
+/// Somewhere cu8 samples come from.
+///
+/// Sources are opened, read from, and closed on the receiver thread, as the
+/// rtl-sdr device can't be moved between threads.
+pub trait SampleSource {
+    fn info(&self) -> SourceInfo;
+
+    /// Fill `buf` with the next samples. Returns the time of the first of them,
+    /// or `None` once there are no more.
+    fn read(&mut self, buf: &mut [u8; DEFAULT_BUF_LENGTH]) -> anyhow::Result<Option<UtcDateTime>>;
+
+    /// Whether samples were lost before those last read, e.g. while the
+    /// source was reconnected.
+    fn gap(&self) -> bool {
+        false
+    }
+
+    /// Bytes of samples in the buffer last read, which only falls short at the
+    /// end of a recording.
+    fn filled(&self) -> usize {
+        DEFAULT_BUF_LENGTH
+    }
+
+    /// What to tell rtl_tcp clients about the dongle.
+    fn dongle(&self) -> DongleInfo {
+        DongleInfo::default()
+    }
+
+    /// Carry out a command from an rtl_tcp client, as far as the source can.
+    /// `info()` describes the source as it is afterwards.
+    fn command(&mut self, command: Command, _value: u32) -> anyhow::Result<()> {
+        debug!("Ignoring {command:?}, as the source can't be retuned");
+        Ok(())
+    }
+
+    /// Retune to `radio`, for hopping between frequencies. `info()` describes
+    /// the source as it is afterwards.
+    fn retune(&mut self, _radio: RadioConfig) -> anyhow::Result<()> {
+        bail!("the source can't be retuned")
+    }
+
+    /// Let go of the hardware, if there is any.
+    fn close(self: Box<Self>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Open the live source `config` says to read from. Sources which wait for
+/// things give up once `shutdown` is set.
+pub fn open<'s>(
+    config: &Config,
+    shutdown: &'s AtomicBool,
+) -> anyhow::Result<Box<dyn SampleSource + 's>> {
+    Ok(match &config.input {
+        Input::Device => Box::new(RtlSdrSource::open(config, shutdown)?),
+        Input::Stdin => Box::new(StdinSource::new(config)),
+        Input::RtlTcp(address) => Box::new(RtlTcpSource::connect(address, config, shutdown)?),
+    })
+}
+
+/// Time of the first sample in a buffer which has just arrived, at `rate`.
+pub fn received_now(rate: u32) -> UtcDateTime {
+    UtcDateTime::now() - Duration::seconds_f64((DEFAULT_BUF_LENGTH / 2) as f64 / f64::from(rate))
+}
+
+/// Read from `reader` until `buf` is full or there's nothing left, returning
+/// how much was read.
+pub fn fill(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// A local rtl-sdr device, which is waited for if it isn't there, and reopened
+/// if it goes away.
+pub struct RtlSdrSource<'s> {
+    /// The device, unless it has been lost and not yet found again
+    sdr: Option<RtlSdr>,
+    index: usize,
+    /// Settings in effect, including any changes from clients, to be applied
+    /// again on reopening; the gain is snapped to one of `gains`
+    settings: Config,
+    radio: RadioConfig,
+    hardware: String,
+    /// Gains the tuner supports, in tenths of a dB
+    gains: Vec<i32>,
+    /// Whether the bias tee is on, and so needs turning off when we're done
+    bias_tee: bool,
+    /// Whether the samples waiting to be read are from before a retune
+    stale: bool,
+    /// Whether samples were lost before the last buffer read
+    gap: bool,
+    /// When we last warned that the device isn't there
+    warned_absent: Option<Instant>,
+    shutdown: &'s AtomicBool,
+}
+
+impl<'s> RtlSdrSource<'s> {
+    /// Open and tune the device picked by `config`, or wait for it to turn up
+    /// on the first read, until `shutdown` is set. It's an error if the device
+    /// is there but can't be used, which waiting won't fix.
+    pub fn open(config: &Config, shutdown: &'s AtomicBool) -> anyhow::Result<Self> {
+        // Get radio and demodulation settings for given frequency and sample rate
+        let radio = optimal_settings(config.frequency, config.sample_rate, config.direct_sampling);
+        let hardware = match &config.serial {
+            Some(serial) => format!("rtl-sdr, serial {serial}"),
+            None => format!("rtl-sdr, index {}", config.device_index),
+        };
+        let mut source = RtlSdrSource {
+            sdr: None,
+            index: config.device_index,
+            settings: config.clone(),
+            radio,
+            hardware,
+            gains: Vec::new(),
+            bias_tee: false,
+            stale: false,
+            gap: false,
+            warned_absent: None,
+            shutdown,
+        };
+        source.connect()?;
+        Ok(source)
+    }
+
+    /// Find the device, open it, and configure it just as it was. Returns
+    /// false if it isn't there to open.
+    fn connect(&mut self) -> anyhow::Result<bool> {
+        // Which may have changed, if it was unplugged
+        let index = match &self.settings.serial {
+            Some(serial) => match device::index_of_serial(serial)? {
+                Some(index) => index,
+                None => {
+                    let reason =
+                        format!("no rtl-sdr device with serial {serial:?} found, see list-devices");
+                    self.absent(&reason);
+                    return Ok(false);
+                }
+            },
+            None => self.settings.device_index,
+        };
+        self.index = index;
+        // Open device
+        let mut sdr = match RtlSdr::open(index) {
+            Ok(sdr) => sdr,
+            Err(e) => {
+                self.absent(&format!("failed to open device {index}: {e:?}"));
+                return Ok(false);
+            }
+        };
+        let gains = sdr
+            .get_tuner_gains()
+            .map_err(|e| anyhow!("failed to list gains of device {index}: {e:?}"))?;
+        debug!("Tuner gains: {} dB", format_gains(&gains));
+        self.settings.gain = snap_gain(self.settings.gain, &gains);
+        // Config receiver
+        config_sdr(&mut sdr, &self.settings, self.radio)
+            .map_err(|e| anyhow!("failed to configure device {index}: {e:?}"))?;
+
+        info!("Tuned to {} Hz.\n", sdr.get_center_freq());
+        info!(
+            "Buffer size: {}ms",
+            1000.0 * 0.5 * DEFAULT_BUF_LENGTH as f32 / self.radio.capture_rate as f32
+        );
+        info!("Sampling at {} S/s", sdr.get_sample_rate());
+
+        self.sdr = Some(sdr);
+        self.gains = gains;
+        self.bias_tee = self.settings.bias_tee;
+        self.stale = false;
+        self.warned_absent = None;
+        Ok(true)
+    }
+
+    /// Say the device isn't there, and that we're waiting for it, but only
+    /// every so often.
+    fn absent(&mut self, reason: &str) {
+        if self
+            .warned_absent
+            .is_some_and(|warned| warned.elapsed() < ABSENT_WARNING_INTERVAL)
+        {
+            debug!("{reason}, retrying");
+            return;
+        }
+        warn!("{reason}; waiting for the device");
+        self.warned_absent = Some(Instant::now());
+    }
+
+    /// Wait for the device to come back, and reopen it. Returns false if we
+    /// were asked to stop first.
+    fn reconnect(&mut self) -> anyhow::Result<bool> {
+        loop {
+            if self.shutdown.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+            thread::sleep(REOPEN_DELAY);
+            if self.connect()? {
+                info!("Reopened device {}", self.index);
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Let go of a device which has stopped working.
+    fn disconnect(&mut self) {
+        if let Some(mut sdr) = self.sdr.take()
+            && let Err(e) = sdr.close()
+        {
+            debug!("closing lost device {}: {e:?}", self.index);
+        }
+        // Along with the device, if it has really gone
+        self.bias_tee = false;
+    }
+
+    fn bias_tee_off(&mut self) -> anyhow::Result<()> {
+        if let (true, Some(sdr)) = (self.bias_tee, &mut self.sdr) {
+            let index = self.index;
+            sdr.set_bias_tee(false)
+                .map_err(|e| anyhow!("failed to turn off bias tee of device {index}: {e:?}"))?;
+            self.bias_tee = false;
+            info!("Turned off bias tee");
+        }
+        Ok(())
+    }
+}
+
+/// Don't leave an LNA powered after a panic, or anything else which skips
+/// `close()`.
+impl Drop for RtlSdrSource<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.bias_tee_off() {
+            error!("{e:#}");
+        }
+    }
+}
+
+/// The supported gain nearest to `gain`, given the tuner's `gains` in tenths
+/// of a dB. Tuners only have a few dozen steps, and otherwise quietly pick
+/// their own, which would leave the recordings' metadata wrong.
+fn snap_gain(gain: Gain, gains: &[i32]) -> Gain {
+    let Gain::Manual(db) = gain else {
+        return gain;
+    };
+    let wanted = (db * 10.).round() as i32;
+    let Some(nearest) = gains
+        .iter()
+        .copied()
+        .min_by_key(|step| step.abs_diff(wanted))
+    else {
+        return gain;
+    };
+    if nearest != wanted {
+        info!(
+            "The tuner can't do {db} dB of gain, using {:.1} dB; it can do {} dB",
+            nearest as f32 / 10.,
+            format_gains(gains)
+        );
+    }
+    Gain::Manual(nearest as f32 / 10.)
+}
+
+/// Tuner gains, in tenths of a dB, as a list in dB.
+pub fn format_gains(gains: &[i32]) -> String {
+    gains
+        .iter()
+        .map(|gain| format!("{:.1}", *gain as f32 / 10.))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl SampleSource for RtlSdrSource<'_> {
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            radio: self.radio,
+            hardware: self.hardware.clone(),
+            tuning: Tuning {
+                // Not yet snapped, if the device hasn't been opened yet
+                gain: Some(self.settings.gain),
+                digital_agc: Some(self.settings.digital_agc),
+                ppm: Some(self.settings.ppm),
+                direct_sampling: Some(self.settings.direct_sampling),
+            },
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8; DEFAULT_BUF_LENGTH]) -> anyhow::Result<Option<UtcDateTime>> {
+        self.gap = false;
+        loop {
+            if let Some(sdr) = &mut self.sdr {
+                match sdr.read_sync(buf) {
+                    // From before a retune
+                    Ok(_) if self.stale => {
+                        self.stale = false;
+                        continue;
+                    }
+                    Ok(DEFAULT_BUF_LENGTH) => {
+                        return Ok(Some(received_now(self.radio.capture_rate)));
+                    }
+                    // The device is still there, but couldn't keep up
+                    Ok(len) if len > 0 => {
+                        warn!(
+                            "Short read ({len}) from device {}, samples lost",
+                            self.index
+                        );
+                        self.gap = true;
+                        continue;
+                    }
+                    Ok(_) => warn!("Device {} stopped sending samples", self.index),
+                    Err(e) => warn!("Reading from device {}: {e:?}", self.index),
+                }
+                self.disconnect();
+                self.gap = true;
+            }
+
+            if !self.reconnect()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn gap(&self) -> bool {
+        self.gap
+    }
+
+    fn dongle(&self) -> DongleInfo {
+        DongleInfo {
+            // The only tuners rtlsdr-rs drives, which it doesn't say more about
+            tuner: TUNER_R820T,
+            // None yet, if the device hasn't been opened yet
+            gain_count: self.gains.len() as u32,
+        }
+    }
+
+    fn command(&mut self, command: Command, value: u32) -> anyhow::Result<()> {
+        command.check(value, self.settings.direct_sampling)?;
+        let Some(sdr) = &mut self.sdr else {
+            bail!("device {} isn't open", self.index);
+        };
+        match command {
+            Command::Frequency => {
+                sdr.set_center_freq(value)
+                    .map_err(|e| anyhow!("failed to tune to {value} Hz: {e:?}"))?;
+                // The client wants the frequency in the middle, so there's no offset
+                self.radio.capture_freq = value;
+                self.radio.offset = 0;
+            }
+            Command::SampleRate => {
+                sdr.set_sample_rate(value)
+                    .map_err(|e| anyhow!("failed to set sample rate {value}: {e:?}"))?;
+                // The offset was a quarter of the old rate, so no longer lines up
+                self.radio.capture_rate = value;
+                self.radio.offset = 0;
+            }
+            // Manual mode takes effect when the client sets a gain
+            Command::GainMode if value == 0 => {
+                sdr.set_tuner_gain(TunerGain::Auto)
+                    .map_err(|e| anyhow!("failed to set auto gain: {e:?}"))?;
+                self.settings.gain = Gain::Auto;
+            }
+            Command::Gain => {
+                let gain = snap_gain(Gain::Manual(value as f32 / 10.), &self.gains);
+                sdr.set_tuner_gain(tuner_gain(gain))
+                    .map_err(|e| anyhow!("failed to set gain: {e:?}"))?;
+                self.settings.gain = gain;
+            }
+            Command::AgcMode => {
+                sdr.set_agc_mode(value != 0)
+                    .map_err(|e| anyhow!("failed to set digital AGC: {e:?}"))?;
+                self.settings.digital_agc = value != 0;
+            }
+            Command::FreqCorrection => {
+                // Sent as a signed number
+                let ppm = value as i32;
+                sdr.set_freq_correction(ppm)
+                    .map_err(|e| anyhow!("failed to set frequency correction {ppm}: {e:?}"))?;
+                self.settings.ppm = ppm;
+            }
+            _ => {
+                debug!("Ignoring {command:?}");
+                return Ok(());
+            }
+        }
+        self.stale = true;
+        Ok(())
+    }
+
+    fn retune(&mut self, radio: RadioConfig) -> anyhow::Result<()> {
+        let Some(sdr) = &mut self.sdr else {
+            bail!("device {} isn't open", self.index);
+        };
+        sdr.set_center_freq(radio.capture_freq)
+            .map_err(|e| anyhow!("failed to tune to {} Hz: {e:?}", radio.capture_freq))?;
+        if radio.capture_rate != self.radio.capture_rate {
+            sdr.set_sample_rate(radio.capture_rate)
+                .map_err(|e| anyhow!("failed to set sample rate {}: {e:?}", radio.capture_rate))?;
+        }
+        self.radio = radio;
+        self.stale = true;
+        Ok(())
+    }
+
+    fn close(mut self: Box<Self>) -> anyhow::Result<()> {
+        let bias_tee = self.bias_tee_off();
+        // Shut down the device
+        info!("Close");
+        let index = self.index;
+        let closed = match &mut self.sdr {
+            Some(sdr) => sdr
+                .close()
+                .map_err(|e| anyhow!("failed to close device {index}: {e:?}")),
+            None => Ok(()),
+        };
+        bias_tee.and(closed)
+    }
+}
+
+/// Configure the SDR device as `config` says, for the receive frequency and
+/// sample rate in `radio`. The gain should be one the tuner supports.
+fn config_sdr(
+    sdr: &mut RtlSdr,
+    config: &Config,
+    radio: RadioConfig,
+) -> rtlsdr_rs::error::Result<()> {
+    sdr.set_tuner_gain(tuner_gain(config.gain))?;
+    sdr.set_agc_mode(config.digital_agc)?;
+    // Rejected if it's unchanged, which it is from the default of 0
+    if config.ppm != 0 {
+        sdr.set_freq_correction(config.ppm)?;
+    }
+    // Before tuning, which then sets the rtl2832's IF instead of the tuner's
+    sdr.set_direct_sampling(match config.direct_sampling {
+        DirectSampling::Off => DirectSampleMode::Off,
+        DirectSampling::I => DirectSampleMode::On,
+        DirectSampling::Q => DirectSampleMode::OnSwap,
+    })?;
+    // Reset the endpoint before we try to read from it (mandatory)
+    sdr.reset_buffer()?;
+    // Set the frequency
+    sdr.set_center_freq(radio.capture_freq)?;
+    // Set sample rate
+    sdr.set_sample_rate(radio.capture_rate)?;
+    // Last, so it isn't left on if anything else fails
+    sdr.set_bias_tee(config.bias_tee)?;
+    Ok(())
+}
+
+/// The tuner takes gains in tenths of a dB.
+fn tuner_gain(gain: Gain) -> TunerGain {
+    match gain {
+        Gain::Auto => TunerGain::Auto,
+        Gain::Manual(db) => TunerGain::Manual((db * 10.).round() as i32),
+    }
+}
+
+/// cu8 samples piped in from `rtl_sdr -`, which has been told the frequency and
+/// sample rate already.
+pub struct StdinSource {
+    stdin: io::Stdin,
+    radio: RadioConfig,
+}
+
+impl StdinSource {
+    pub fn new(config: &Config) -> Self {
+        StdinSource {
+            stdin: io::stdin(),
+            radio: RadioConfig {
+                capture_freq: config.frequency,
+                capture_rate: config.sample_rate,
+                offset: 0,
+            },
+        }
+    }
+}
+
+impl SampleSource for StdinSource {
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            radio: self.radio,
+            hardware: "rtl-sdr, via stdin".to_string(),
+            // Whatever rtl_sdr was told
+            tuning: Tuning::default(),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8; DEFAULT_BUF_LENGTH]) -> anyhow::Result<Option<UtcDateTime>> {
+        let filled = fill(&mut self.stdin.lock(), buf)?;
+        if filled == 0 {
+            return Ok(None);
+        }
+        // The other end has gone away part way through a buffer
+        buf[filled..].fill(128);
+        Ok(Some(received_now(self.radio.capture_rate)))
+    }
+}
+
+/// Sample encodings we can read, all of which get converted to cu8.
+#[derive(Copy, Clone, Debug)]
+enum Encoding {
+    Cu8,
+    Ci8,
+    Ci16Le,
+    Cf32Le,
+}
+
+impl Encoding {
+    fn from_datatype(datatype: &str) -> Option<Self> {
+        Some(match datatype {
+            "cu8" => Encoding::Cu8,
+            "ci8" => Encoding::Ci8,
+            "ci16_le" => Encoding::Ci16Le,
+            "cf32_le" => Encoding::Cf32Le,
+            _ => return None,
+        })
+    }
+
+    /// Bytes taken by each of I and Q.
+    fn width(self) -> usize {
+        match self {
+            Encoding::Cu8 | Encoding::Ci8 => 1,
+            Encoding::Ci16Le => 2,
+            Encoding::Cf32Le => 4,
+        }
+    }
+
+    /// Convert `input` to cu8, one byte of `output` per I or Q.
+    fn to_cu8(self, input: &[u8], output: &mut [u8]) {
+        let chunks = input.chunks_exact(self.width()).zip(output);
+        match self {
+            Encoding::Cu8 => {
+                for (from, to) in chunks {
+                    *to = from[0];
+                }
+            }
+            Encoding::Ci8 => {
+                for (from, to) in chunks {
+                    *to = from[0] ^ 0x80;
+                }
+            }
+            Encoding::Ci16Le => {
+                for (from, to) in chunks {
+                    let value = i16::from_le_bytes([from[0], from[1]]);
+                    *to = ((value >> 8) + 128) as u8;
+                }
+            }
+            Encoding::Cf32Le => {
+                for (from, to) in chunks {
+                    let value = f32::from_le_bytes([from[0], from[1], from[2], from[3]]);
+                    *to = (value * 127.5 + 127.5).round().clamp(0., 255.) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// A recording on disk, read back a buffer at a time as if it was coming from
+/// the receiver.
+pub struct FileSource {
+    reader: io::Take<BufReader<File>>,
+    encoding: Encoding,
+    /// Frequency the recording is centred on, in Hz
+    pub frequency: u32,
+    pub sample_rate: u32,
+    /// Time of the first sample
+    pub start: UtcDateTime,
+    /// Samples read so far
+    sample: u64,
+    /// File name, for the recordings' metadata
+    name: String,
+    /// Scratch space for samples before they're converted
+    raw: Vec<u8>,
+    /// Bytes of samples in the buffer last read
+    filled: usize,
+}
+
+impl FileSource {
+    /// Open a `.cu8`, `.wav` or SigMF recording.
+    ///
+    /// WAV and SigMF files say what their sample rate is, and SigMF files their
+    /// frequency and start time. Anything else comes from `config`, or, for the
+    /// start time, from when the file was last modified.
+    pub fn open(path: &Path, config: &Config) -> anyhow::Result<Self> {
+        let extension = path.extension().and_then(OsStr::to_str).unwrap_or("");
+        let source = match extension {
+            "wav" => Self::open_wav(path, config),
+            sigmf::DATA_EXTENSION | sigmf::META_EXTENSION => Self::open_sigmf(path),
+            _ => {
+                let file = File::open(path)?;
+                let len = file.metadata()?.len();
+                let reader = BufReader::new(file);
+                Self::new(
+                    path,
+                    reader,
+                    len,
+                    Encoding::Cu8,
+                    config.frequency,
+                    config.sample_rate,
+                )
+            }
+        };
+        source.with_context(|| format!("reading {}", path.display()))
+    }
+
+    /// Read `len` bytes of samples from `reader`, which is positioned at the
+    /// first of them in the file at `path`.
+    fn new(
+        path: &Path,
+        reader: BufReader<File>,
+        len: u64,
+        encoding: Encoding,
+        frequency: u32,
+        sample_rate: u32,
+    ) -> anyhow::Result<Self> {
+        ensure!(sample_rate > 0, "sample rate must be non-zero");
+        let duration = Duration::seconds_f64(
+            len as f64 / (2 * encoding.width()) as f64 / f64::from(sample_rate),
+        );
+        let start = UtcDateTime::from(path.metadata()?.modified()?) - duration;
+        Ok(FileSource {
+            reader: reader.take(len),
+            encoding,
+            frequency,
+            sample_rate,
+            start,
+            sample: 0,
+            name: path
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+            raw: Vec::new(),
+            filled: 0,
+        })
+    }
+
+    fn open_wav(path: &Path, config: &Config) -> anyhow::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        ensure!(
+            &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE",
+            "not a WAV file"
+        );
+
+        let mut format = None;
+        loop {
+            let mut chunk = [0; 8];
+            reader
+                .read_exact(&mut chunk)
+                .context("no data chunk in WAV file")?;
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            // Chunks are padded to an even length
+            let padded = i64::from(size) + i64::from(size & 1);
+            match &chunk[0..4] {
+                b"fmt " => {
+                    ensure!(size >= 16, "WAV format chunk too short");
+                    let mut fmt = [0; 16];
+                    reader.read_exact(&mut fmt)?;
+                    reader.seek_relative(padded - 16)?;
+                    let tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+                    let channels = u16::from_le_bytes([fmt[2], fmt[3]]);
+                    let rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                    let bits = u16::from_le_bytes([fmt[14], fmt[15]]);
+                    ensure!(
+                        channels == 2,
+                        "WAV file must be stereo, I and Q, not {channels} channels"
+                    );
+                    let encoding = match (tag, bits) {
+                        (1, 8) => Encoding::Cu8,
+                        (1, 16) => Encoding::Ci16Le,
+                        (3, 32) => Encoding::Cf32Le,
+                        _ => bail!("unsupported WAV encoding {tag} with {bits} bit samples"),
+                    };
+                    format = Some((encoding, rate));
+                }
+                b"data" => {
+                    let Some((encoding, rate)) = format else {
+                        bail!("WAV data chunk before format chunk");
+                    };
+                    return Self::new(
+                        path,
+                        reader,
+                        u64::from(size),
+                        encoding,
+                        config.frequency,
+                        rate,
+                    );
+                }
+                _ => reader.seek_relative(padded)?,
+            }
+        }
+    }
+
+    fn open_sigmf(path: &Path) -> anyhow::Result<Self> {
+        let meta = sigmf::Source::read(&path.with_extension(sigmf::META_EXTENSION))?;
+        let Some(encoding) = Encoding::from_datatype(&meta.global.datatype) else {
+            bail!("unsupported SigMF datatype {:?}", meta.global.datatype);
+        };
+        let capture = meta.captures.first();
+        let Some(frequency) = capture.and_then(|capture| capture.frequency) else {
+            bail!("SigMF metadata has no capture frequency");
+        };
+
+        let data_path = path.with_extension(sigmf::DATA_EXTENSION);
+        let file = File::open(&data_path)?;
+        let len = file.metadata()?.len();
+        let mut source = Self::new(
+            &data_path,
+            BufReader::new(file),
+            len,
+            encoding,
+            frequency.round() as u32,
+            meta.global.sample_rate.round() as u32,
+        )?;
+        if let Some(datetime) = capture.and_then(|capture| capture.datetime.as_deref()) {
+            source.start = UtcDateTime::parse(datetime, &Rfc3339)
+                .with_context(|| format!("parsing SigMF datetime {datetime:?}"))?;
+        }
+        Ok(source)
+    }
+}
+
+impl SampleSource for FileSource {
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            // The recording is already where it is, so there's no tuning offset
+            radio: RadioConfig {
+                capture_freq: self.frequency,
+                capture_rate: self.sample_rate,
+                offset: 0,
+            },
+            hardware: format!("replayed from {}", self.name),
+            tuning: Tuning::default(),
+        }
+    }
+
+    /// Read the next samples; the last buffer is only partly filled.
+    fn read(&mut self, buf: &mut [u8; DEFAULT_BUF_LENGTH]) -> anyhow::Result<Option<UtcDateTime>> {
+        let width = self.encoding.width();
+        self.raw.resize(buf.len() * width, 0);
+        let filled = fill(&mut self.reader, &mut self.raw)?;
+        // Whole I/Q pairs only, in case the file was cut off mid-sample
+        let converted = filled / width / 2 * 2;
+        if converted == 0 {
+            return Ok(None);
+        }
+        self.encoding
+            .to_cu8(&self.raw[..converted * width], &mut buf[..converted]);
+        self.filled = converted;
+
+        let start =
+            self.start + Duration::seconds_f64(self.sample as f64 / f64::from(self.sample_rate));
+        self.sample += (converted / 2) as u64;
+        Ok(Some(start))
+    }
+
+    fn filled(&self) -> usize {
+        self.filled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A WAV file with a format chunk for `channels` 16-bit channels at
+    /// 250 kS/s, after an odd-length chunk to skip, then `samples`.
+    fn wav(channels: u16, samples: &[i16]) -> Vec<u8> {
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&1u16.to_le_bytes());
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&250_000u32.to_le_bytes());
+        fmt.extend_from_slice(&(250_000 * 2 * u32::from(channels)).to_le_bytes());
+        fmt.extend_from_slice(&(2 * channels).to_le_bytes());
+        fmt.extend_from_slice(&16u16.to_le_bytes());
+        let data = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<u8>>();
+
+        let mut chunks = Vec::new();
+        for (id, body) in [(b"LIST", &b"odd"[..]), (b"fmt ", &fmt), (b"data", &data)] {
+            chunks.extend_from_slice(id);
+            chunks.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            chunks.extend_from_slice(body);
+            if body.len() % 2 == 1 {
+                chunks.push(0);
+            }
+        }
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+        file.extend_from_slice(b"WAVE");
+        file.extend_from_slice(&chunks);
+        file
+    }
+
+    /// Write `contents` to a file of its own in the temporary directory.
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rtl-sdr-snipper-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn open_wav_reads_16_bit_iq() {
+        let path = temp_file("iq.wav", &wav(2, &[0x7f00, i16::MIN, 0, 0x7f00 / 2]));
+        let config = Config::default();
+        let mut source = FileSource::open(&path, &config);
+        fs::remove_file(&path).unwrap();
+        let source = source.as_mut().unwrap();
+        assert_eq!(source.sample_rate, 250_000);
+        assert_eq!(source.frequency, config.frequency);
+
+        let mut buf = [0; DEFAULT_BUF_LENGTH];
+        assert!(source.read(&mut buf).unwrap().is_some());
+        assert_eq!(buf[..4], [255, 0, 128, 191]);
+        // Only as far as the file goes
+        assert_eq!(source.filled(), 4);
+        assert!(source.read(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn open_wav_rejects_mono() {
+        let path = temp_file("mono.wav", &wav(1, &[0, 0]));
+        let source = FileSource::open(&path, &Config::default());
+        fs::remove_file(&path).unwrap();
+        let Err(e) = source else {
+            panic!("mono WAV file was accepted");
+        };
+        assert!(format!("{e:#}").contains("1 channels"));
+    }
+}
@@ -0,0 +1,461 @@
+//! Writing recordings out on a thread of their own, so detection carries on
+//! while the disk, like a slow SD card, catches up.
+//!
+//! Files are written under a temporary name, and only renamed into place once
+//! they're complete and synced to disk, the metadata last, so a recording cut
+//! off by a crash or a power cut never looks like a whole one.
+
+use crate::config::SampleFormat;
+use crate::dsp::{self, Ddc};
+use crate::sigmf;
+use anyhow::{Context, anyhow, bail};
+use log::{debug, info, warn};
+use num_complex::Complex;
+use rtlsdr_rs::DEFAULT_BUF_LENGTH;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, TryLockError};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+/// Operations queued for the writer before the processor waits for it. Each
+/// write holds on to a receiver buffer, so this also bounds the memory used
+/// while the disk is behind.
+const QUEUE_DEPTH: usize = 64;
+
+/// Added to the names of files while they're being written.
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// Held, shared, by every snipper writing to a directory, so only the first
+/// tidies up after earlier runs.
+const LOCK_FILE: &str = ".rtl-sdr-snipper.lock";
+
+/// What happens to samples on their way to disk.
+pub enum Conversion {
+    /// Written as received
+    Raw,
+    /// Shifted up by a quarter of the sample rate, undoing the tuning offset
+    ShiftUp,
+    /// Down-converted, and written as `format`
+    DownConvert { ddc: Ddc, format: SampleFormat },
+}
+
+enum Op {
+    /// Start writing recording `id` to `path`
+    Create {
+        id: u64,
+        path: PathBuf,
+        conversion: Conversion,
+    },
+    /// Start writing recording `id` to `path`, converting as recording `from`
+    /// did, carrying on where it left off
+    CarryOn { id: u64, from: u64, path: PathBuf },
+    /// Write `range` of the bytes in `data`, starting at receiver sample `sample`
+    Write {
+        id: u64,
+        data: Arc<[u8; DEFAULT_BUF_LENGTH]>,
+        range: Range<usize>,
+        sample: u64,
+    },
+    /// Flush the samples to disk, then write the metadata
+    Finish { id: u64, meta: sigmf::Meta },
+}
+
+impl Op {
+    fn id(&self) -> u64 {
+        match self {
+            Op::Create { id, .. }
+            | Op::CarryOn { id, .. }
+            | Op::Write { id, .. }
+            | Op::Finish { id, .. } => *id,
+        }
+    }
+}
+
+/// The writer thread, which carries out whatever it's asked to, in order.
+pub struct Writer {
+    ops: Option<SyncSender<Op>>,
+    /// Failures writing recordings, each of which is given up on
+    errors: Receiver<anyhow::Error>,
+    thread: Option<JoinHandle<()>>,
+    next_id: Cell<u64>,
+}
+
+impl Writer {
+    /// Start the writer thread, named after this one, to say which device its
+    /// messages are about.
+    pub fn start() -> Self {
+        let (ops_tx, ops_rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        let (errors_tx, errors_rx) = mpsc::channel();
+        let mut builder = thread::Builder::new();
+        if let Some(name) = thread::current().name() {
+            builder = builder.name(name.to_string());
+        }
+        let thread = builder
+            .spawn(move || write(ops_rx, errors_tx))
+            .expect("failed to spawn writer");
+        Writer {
+            ops: Some(ops_tx),
+            errors: errors_rx,
+            thread: Some(thread),
+            next_id: Cell::new(0),
+        }
+    }
+
+    /// Start a recording at `path`, returning the id to write to it with.
+    pub fn create(&self, path: PathBuf, conversion: Conversion) -> anyhow::Result<u64> {
+        let id = self.next_id();
+        self.send(Op::Create {
+            id,
+            path,
+            conversion,
+        })?;
+        Ok(id)
+    }
+
+    /// Start a recording at `path` carrying on from recording `from`, so
+    /// there's no join in the samples.
+    pub fn carry_on(&self, from: u64, path: PathBuf) -> anyhow::Result<u64> {
+        let id = self.next_id();
+        self.send(Op::CarryOn { id, from, path })?;
+        Ok(id)
+    }
+
+    /// Write `range` of the bytes in `data` to recording `id`, where `sample`
+    /// is the index of the receiver sample the range starts at.
+    pub fn write(
+        &self,
+        id: u64,
+        data: &Arc<[u8; DEFAULT_BUF_LENGTH]>,
+        range: Range<usize>,
+        sample: u64,
+    ) -> anyhow::Result<()> {
+        self.send(Op::Write {
+            id,
+            data: Arc::clone(data),
+            range,
+            sample,
+        })
+    }
+
+    /// Finish recording `id`, with `meta` written once its samples are on disk.
+    pub fn finish(&self, id: u64, meta: sigmf::Meta) -> anyhow::Result<()> {
+        self.send(Op::Finish { id, meta })
+    }
+
+    /// The first failure writing a recording since last asked, if any.
+    pub fn check(&self) -> anyhow::Result<()> {
+        match self.errors.try_recv() {
+            Ok(e) => Err(e),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Wait for everything queued to be written out.
+    pub fn close(mut self) -> anyhow::Result<()> {
+        self.ops.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+        self.check()
+    }
+
+    fn next_id(&self) -> u64 {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        id
+    }
+
+    fn send(&self, op: Op) -> anyhow::Result<()> {
+        let ops = self.ops.as_ref().expect("writer is open");
+        match ops.try_send(op) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(op)) => {
+                debug!("Waiting for the writer to catch up");
+                ops.send(op).map_err(|_| anyhow!("writer stopped"))
+            }
+            Err(TrySendError::Disconnected(_)) => bail!("writer stopped"),
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Still write out whatever was queued, even if processing failed
+        self.ops.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A recording the writer has open.
+struct Output {
+    /// Where the samples go once they're complete
+    path: PathBuf,
+    file: BufWriter<fs::File>,
+    conversion: Conversion,
+}
+
+/// Carry out `ops` until the queue is closed, reporting failures to `errors`.
+fn write(ops: Receiver<Op>, errors: mpsc::Sender<anyhow::Error>) {
+    let mut state = State {
+        outputs: HashMap::new(),
+        bytes: Vec::new(),
+        samples: Vec::new(),
+    };
+    for op in ops {
+        let id = op.id();
+        if let Err(e) = state.apply(op) {
+            // Give up on the recording; anything else for it is ignored
+            if let Some(output) = state.outputs.remove(&id) {
+                let _ = fs::remove_file(partial(&output.path));
+            }
+            let _ = errors.send(e);
+        }
+    }
+}
+
+struct State {
+    outputs: HashMap<u64, Output>,
+    /// Scratch space for shifted samples
+    bytes: Vec<u8>,
+    /// Scratch space for down-converted samples
+    samples: Vec<Complex<f32>>,
+}
+
+impl State {
+    fn apply(&mut self, op: Op) -> anyhow::Result<()> {
+        match op {
+            Op::Create {
+                id,
+                path,
+                conversion,
+            } => self.open(id, path, conversion),
+            Op::CarryOn { id, from, path } => {
+                let Some(previous) = self.outputs.get_mut(&from) else {
+                    return Ok(());
+                };
+                // Nothing more is written to the previous recording
+                let conversion = mem::replace(&mut previous.conversion, Conversion::Raw);
+                self.open(id, path, conversion)
+            }
+            Op::Write {
+                id,
+                data,
+                range,
+                sample,
+            } => {
+                let Some(output) = self.outputs.get_mut(&id) else {
+                    return Ok(());
+                };
+                let data = &data[range];
+                let written = match &mut output.conversion {
+                    Conversion::Raw => output.file.write_all(data),
+                    Conversion::ShiftUp => {
+                        self.bytes.clear();
+                        self.bytes.extend_from_slice(data);
+                        dsp::shift_up_quarter_rate(&mut self.bytes, sample);
+                        output.file.write_all(&self.bytes)
+                    }
+                    Conversion::DownConvert { ddc, format } => {
+                        self.samples.clear();
+                        ddc.process(data, &mut self.samples);
+                        dsp::write_samples(*format, &self.samples, &mut output.file)
+                    }
+                };
+                written.with_context(|| format!("writing {}", output.path.display()))
+            }
+            Op::Finish { id, meta } => {
+                let Some(mut output) = self.outputs.remove(&id) else {
+                    return Ok(());
+                };
+                // Both files are safe on disk before either is renamed, so
+                // if we're cut off between the renames, `sweep` can finish
+                // the job
+                let meta_path = output.path.with_extension(sigmf::META_EXTENSION);
+                let finished = output
+                    .file
+                    .flush()
+                    .and_then(|()| output.file.get_ref().sync_all())
+                    .with_context(|| format!("writing {}", output.path.display()))
+                    .and_then(|()| {
+                        meta.write(&partial(&meta_path))
+                            .with_context(|| format!("writing {}", meta_path.display()))
+                    })
+                    .and_then(|()| {
+                        rename_into_place(&output.path)
+                            .and_then(|()| rename_into_place(&meta_path))
+                            .with_context(|| format!("renaming {}", output.path.display()))
+                    });
+                if finished.is_err() {
+                    // Samples without their metadata would look like a whole
+                    // recording to anything but us
+                    for path in [partial(&output.path), output.path, partial(&meta_path)] {
+                        let _ = fs::remove_file(path);
+                    }
+                }
+                finished
+            }
+        }
+    }
+
+    fn open(&mut self, id: u64, path: PathBuf, conversion: Conversion) -> anyhow::Result<()> {
+        let partial = partial(&path);
+        let file = fs::File::create(&partial)
+            .with_context(|| format!("creating {}", partial.display()))?;
+        self.outputs.insert(
+            id,
+            Output {
+                path,
+                file: BufWriter::new(file),
+                conversion,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Where `path` is written until it's complete.
+fn partial(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".");
+    partial.push(PARTIAL_EXTENSION);
+    PathBuf::from(partial)
+}
+
+/// Rename the finished `partial(path)` to `path`, and make sure the rename
+/// itself survives a power cut.
+fn rename_into_place(path: &Path) -> io::Result<()> {
+    fs::rename(partial(path), path)?;
+    // Only Unix can open directories to sync them
+    if cfg!(unix) {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Take a shared lock on `dir` for as long as the returned file is open,
+/// first tidying up after an earlier run if no other snipper is writing there.
+pub fn lock(dir: &Path) -> anyhow::Result<fs::File> {
+    let path = dir.join(LOCK_FILE);
+    let file = fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("creating {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => sweep(dir)?,
+        Err(TryLockError::WouldBlock) => {
+            info!(
+                "Another snipper is writing to {}, so not tidying up",
+                dir.display()
+            );
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("locking {}", path.display()));
+        }
+    }
+    // Converts the exclusive lock, if we have it, so other snippers can share
+    file.lock_shared()
+        .with_context(|| format!("locking {}", path.display()))?;
+    Ok(file)
+}
+
+/// Tidy up the temporary files in `dir` left by an earlier run which was
+/// killed, crashed, or lost power: finish renaming metadata whose samples were
+/// renamed into place, and delete the rest.
+fn sweep(dir: &Path) -> anyhow::Result<()> {
+    let data_suffix = format!(".{}.{PARTIAL_EXTENSION}", sigmf::DATA_EXTENSION);
+    let meta_suffix = format!(".{}.{PARTIAL_EXTENSION}", sigmf::META_EXTENSION);
+    let entries = fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))?;
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("reading {}", dir.display()))?
+            .path();
+        let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+            continue;
+        };
+        if name.ends_with(&meta_suffix) {
+            let meta_path = path.with_extension("");
+            if meta_path.with_extension(sigmf::DATA_EXTENSION).exists() {
+                warn!(
+                    "Finishing {}, left incomplete by an earlier run",
+                    meta_path.display()
+                );
+                rename_into_place(&meta_path)
+                    .with_context(|| format!("renaming {}", path.display()))?;
+                continue;
+            }
+        } else if !name.ends_with(&data_suffix) {
+            continue;
+        }
+        warn!(
+            "Deleting {}, left incomplete by an earlier run",
+            path.display()
+        );
+        fs::remove_file(&path).with_context(|| format!("deleting {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_only_tidies_up_temporaries() {
+        let dir =
+            std::env::temp_dir().join(format!("rtl-sdr-snipper-sweep-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let names = [
+            ("whole.sigmf-data", true),
+            ("whole.sigmf-meta", true),
+            ("partial.sigmf-data.partial", false),
+            ("partial.sigmf-meta.partial", false),
+            ("renaming.sigmf-data", true),
+            ("renaming.sigmf-meta.partial", false),
+            ("renaming.sigmf-meta", true),
+            ("unrelated.sigmf-data", true),
+            ("capture.cu8", true),
+        ];
+        for (name, _) in names {
+            if !name.starts_with("renaming.sigmf-meta") || name.ends_with(".partial") {
+                fs::write(dir.join(name), b"").unwrap();
+            }
+        }
+
+        let locked = lock(&dir);
+        let left = names.map(|(name, _)| dir.join(name).exists());
+        fs::remove_dir_all(&dir).unwrap();
+        locked.unwrap();
+        assert_eq!(left, names.map(|(_, kept)| kept));
+    }
+
+    #[test]
+    fn sweep_leaves_a_live_snipper_alone() {
+        let dir = std::env::temp_dir().join(format!("rtl-sdr-snipper-live-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let live = lock(&dir).unwrap();
+        let writing = dir.join("writing.sigmf-data.partial");
+        fs::write(&writing, b"").unwrap();
+
+        let locked = lock(&dir);
+        let left = writing.exists();
+        drop(live);
+        fs::remove_dir_all(&dir).unwrap();
+        locked.unwrap();
+        assert!(left);
+    }
+}